@@ -0,0 +1,479 @@
+use crate::{Client, InfuserError};
+use chrono::{DateTime, NaiveTime, TimeZone, Timelike, Utc};
+use cron::{Schedule, TimeUnitSpec};
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// Whether a client can receive jobs and if not, why
+///
+/// When several reasons apply, the first one in declaration order is reported.
+/// Operator decisions come before liveness and liveness before the daily schedule.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvailabilityState {
+    /// the client is draining and doesn't accept new jobs
+    Draining,
+    /// the client is in a blackout period
+    Blackout,
+    /// the client is offline and doesn't ignore its online state
+    Offline,
+    /// the current time is outside the client's daily availability window
+    OutsideWindow,
+    Available,
+}
+
+/// A maximum job count that applies instead of `Client::maximum_jobs` during a daily window
+///
+/// The window follows the rules of the availability window, see `Client::within_availability_window`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct CapacityOverride {
+    pub start: String,
+    pub end: String,
+    pub maximum_jobs: i32,
+}
+
+impl CapacityOverride {
+    /// Checks whether the override applies at `now`, a malformed window never applies
+    pub fn applies_at<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        match (parse_time_of_day(&self.start), parse_time_of_day(&self.end)) {
+            (Ok(start), Ok(end)) => window_contains(start, end, now.time()),
+            _ => false,
+        }
+    }
+}
+
+/// Parses a time of day as used by `availability_start` and `availability_end`,
+/// either `HH:MM` or `HH:MM:SS`
+pub fn parse_time_of_day(value: &str) -> Result<NaiveTime, InfuserError> {
+    let value = value.trim();
+    NaiveTime::parse_from_str(value, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M"))
+        .map_err(|e| InfuserError::with_source(&format!("invalid time of day {:?}, expected HH:MM", value), e))
+}
+
+/// Collects the clients whose availability cron, window or capacity schedule can't be parsed
+///
+/// Malformed windows don't fail scheduling, see `Client::within_availability_window` and
+/// `CapacityOverride::applies_at`, so run this after loading the fleet, e.g. at startup,
+/// to get them fixed instead of silently ignored. Each client is reported once, with its first error.
+pub fn validate_client_availability(clients: &[Client]) -> Vec<(Client, String)> {
+    clients
+        .iter()
+        .filter_map(|client| {
+            let error = client.availability_schedule().err().or_else(|| client.availability_window().err()).or_else(|| {
+                client.capacity_schedule.iter().find_map(|entry| {
+                    parse_time_of_day(&entry.start).and(parse_time_of_day(&entry.end)).err()
+                })
+            })?;
+            Some((client.clone(), error.to_string()))
+        })
+        .collect()
+}
+
+/// The client whose availability starts first and how long that takes, e.g. to sleep until then
+///
+/// Clients that are available at `now` have a duration of zero. A client in a blackout with an end
+/// becomes available at the start of its availability after the blackout. Draining clients, offline
+/// clients that don't ignore their online state and blackouts without an end can't be predicted
+/// from the clock and are skipped, `None` means no client can be expected to become available.
+pub fn next_available_client<'a, Tz: TimeZone>(clients: &'a [Client], now: &DateTime<Tz>) -> Option<(&'a Client, Duration)> {
+    clients
+        .iter()
+        .filter_map(|client| match client.availability_state(now) {
+            AvailabilityState::Available => Some((client, Duration::ZERO)),
+            AvailabilityState::OutsideWindow => client.time_until_available(now).map(|until| (client, until)),
+            AvailabilityState::Blackout => {
+                if !client.online && !client.ignore_online {
+                    return None;
+                }
+                let end = client.blackout_end?.0;
+                let until_end = (end - now.with_timezone(&Utc)).to_std().ok()?;
+                let after_blackout = if client.ignore_availability {
+                    Duration::ZERO
+                } else {
+                    client.time_until_available(&end.with_timezone(&now.timezone())).unwrap_or(Duration::ZERO)
+                };
+                Some((client, until_end + after_blackout))
+            }
+            AvailabilityState::Draining | AvailabilityState::Offline => None,
+        })
+        .min_by_key(|(_, until)| *until)
+}
+
+impl Client {
+    /// Computes whether the client can receive jobs at `now`, see `AvailabilityState` for the precedence
+    ///
+    /// Job counts are not taken into account, a full client can still be `Available`.
+    pub fn availability_state<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> AvailabilityState {
+        if self.draining {
+            AvailabilityState::Draining
+        } else if self.in_blackout(now) {
+            AvailabilityState::Blackout
+        } else if !self.online && !self.ignore_online {
+            AvailabilityState::Offline
+        } else if !self.ignore_availability && !self.within_availability_window(now) {
+            AvailabilityState::OutsideWindow
+        } else {
+            AvailabilityState::Available
+        }
+    }
+
+    /// Parses the daily availability window
+    ///
+    /// Returns `None` if neither start nor end are set, meaning the client is available all day.
+    /// Setting only one of them is an error.
+    pub fn availability_window(&self) -> Result<Option<(NaiveTime, NaiveTime)>, InfuserError> {
+        match (self.availability_start.trim(), self.availability_end.trim()) {
+            ("", "") => Ok(None),
+            ("", _) | (_, "") => Err(InfuserError::new(&format!(
+                "availability window of client {} needs both a start and an end",
+                self.name
+            ))),
+            (start, end) => Ok(Some((parse_time_of_day(start)?, parse_time_of_day(end)?))),
+        }
+    }
+
+    /// Parses `availability_cron`, `None` if the client has none
+    pub fn availability_schedule(&self) -> Result<Option<Schedule>, InfuserError> {
+        match &self.availability_cron {
+            Some(expression) => Schedule::from_str(expression).map(Some).map_err(|e| {
+                InfuserError::with_source(&format!("invalid availability cron {:?} of client {}", expression, self.name), e)
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks whether `now` lies within the client's availability
+    ///
+    /// If `availability_cron` is set it wins and the start and end strings are ignored:
+    /// the client is available whenever `now` matches every field of the expression, seconds included.
+    /// Fields are `sec min hour day-of-month month day-of-week [year]`, so weekdays from 8 am to 6 pm
+    /// are `* * 8-17 * * Mon-Fri`.
+    ///
+    /// Otherwise the daily window is compared against the wall clock time of `now` in its own timezone.
+    /// The start is inclusive and the end exclusive, a window whose end is before its start
+    /// wraps past midnight, e.g. 22:00 - 06:00. Equal start and end cover the whole day.
+    /// Clients without a window or with a malformed window or cron expression are always within it,
+    /// so a typo never takes a client out of rotation; use `validate_client_availability` to detect those.
+    pub fn within_availability_window<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        match self.availability_schedule() {
+            Ok(Some(schedule)) => return schedule.includes(now.clone()),
+            Ok(None) => (),
+            Err(_) => return true,
+        }
+        match self.availability_window() {
+            Ok(Some((start, end))) => window_contains(start, end, now.time()),
+            _ => true,
+        }
+    }
+
+    /// Checks whether the client's availability covers the whole range from `start` to `end` (exclusive),
+    /// e.g. to plan a batch for the night instead of asking about `now`
+    ///
+    /// A range that only partly overlaps the window is not covered. Windows past midnight cover ranges
+    /// past midnight, a range of a day or longer is only covered by a whole day window. With a cron
+    /// expression every second of the range has to match, see `matching_block_end` for how that is
+    /// checked without visiting every second. Like `within_availability_window`, clients
+    /// without a window or with a malformed one are available at any time.
+    /// An `end` before or at `start` checks `start` only.
+    pub fn available_during<Tz: TimeZone>(&self, start: &DateTime<Tz>, end: &DateTime<Tz>) -> bool {
+        if !self.within_availability_window(start) {
+            return false;
+        }
+        if let Ok(Some(schedule)) = self.availability_schedule() {
+            let mut next = start.clone();
+            while next < *end {
+                if !schedule.includes(next.clone()) {
+                    return false;
+                }
+                next = matching_block_end(&schedule, &next);
+            }
+            return true;
+        }
+        match self.availability_window() {
+            Ok(Some((window_start, window_end))) if window_start != window_end => {
+                let mut until_window_end = window_end - start.time();
+                if until_window_end <= chrono::Duration::zero() {
+                    until_window_end += chrono::Duration::days(1);
+                }
+                end.clone() - start.clone() <= until_window_end
+            }
+            _ => true,
+        }
+    }
+
+    /// The time until the client's availability starts again, `None` if it is available at `now`
+    ///
+    /// Only the availability cron or window is considered, see `within_availability_window`, not the
+    /// online state or blackouts. A window that already started today opens again tomorrow, for windows
+    /// past midnight like 22:00 - 06:00 the countdown is to 22:00. For a cron expression it is the time
+    /// until the next matching second; an expression that never matches again yields `None`.
+    /// Clients with `ignore_availability` are always available.
+    pub fn time_until_available<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Option<Duration> {
+        if self.ignore_availability || self.within_availability_window(now) {
+            return None;
+        }
+        if let Ok(Some(schedule)) = self.availability_schedule() {
+            let next = schedule.after(now).next()?;
+            return (next - now.clone()).to_std().ok();
+        }
+        let (start, _) = self.availability_window().ok()??;
+        let mut until = start - now.time();
+        if until <= chrono::Duration::zero() {
+            until += chrono::Duration::days(1);
+        }
+        until.to_std().ok()
+    }
+
+    /// The maximum job count at `now`
+    ///
+    /// The first entry of `capacity_schedule` whose window contains `now` wins,
+    /// outside of all windows `maximum_jobs` applies.
+    pub fn maximum_jobs_at<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> i32 {
+        self.capacity_schedule
+            .iter()
+            .find(|entry| entry.applies_at(now))
+            .map_or(self.maximum_jobs, |entry| entry.maximum_jobs)
+    }
+}
+
+/// The end of the largest block of time around `at` that matches `schedule` as a whole, `at` matching
+///
+/// If every second of a minute is part of the expression, a matching second means its whole minute
+/// matches, the same goes for the minutes of an hour and the hours of a day. So a range is checked
+/// one block at a time: a few seconds, minutes or hours until the first gap, and at most one step
+/// per day for expressions that only restrict days.
+fn matching_block_end<Tz: TimeZone>(schedule: &Schedule, at: &DateTime<Tz>) -> DateTime<Tz> {
+    let all_seconds = (0..60).all(|second| schedule.seconds().includes(second));
+    let all_minutes = all_seconds && (0..60).all(|minute| schedule.minutes().includes(minute));
+    let all_hours = all_minutes && (0..24).all(|hour| schedule.hours().includes(hour));
+    let (block, elapsed) = if all_hours {
+        (86_400, at.num_seconds_from_midnight())
+    } else if all_minutes {
+        (3_600, at.minute() * 60 + at.second())
+    } else if all_seconds {
+        (60, at.second())
+    } else {
+        (1, 0)
+    };
+    // on days with a daylight saving change this may end an hour early or late, which only moves
+    // the next check within a day that matches as a whole or into the next day
+    at.clone() + chrono::Duration::seconds(i64::from(block - elapsed))
+}
+
+fn window_contains(start: NaiveTime, end: NaiveTime, time: NaiveTime) -> bool {
+    if start <= end {
+        start == end || (start <= time && time < end)
+    } else {
+        time >= start || time < end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(start: &str, end: &str) -> Client {
+        Client {
+            name: "client".to_string(),
+            availability_start: start.to_string(),
+            availability_end: end.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_availability_state() {
+        let noon = at(12, 0);
+        let available = Client { online: true, ..client("08:00", "18:00") };
+        assert_eq!(available.availability_state(&noon), AvailabilityState::Available);
+        assert_eq!(available.availability_state(&at(20, 0)), AvailabilityState::OutsideWindow);
+
+        let offline = Client { online: false, ..available.clone() };
+        assert_eq!(offline.availability_state(&noon), AvailabilityState::Offline);
+        let ignore_online = Client { ignore_online: true, ..offline.clone() };
+        assert_eq!(ignore_online.availability_state(&noon), AvailabilityState::Available);
+        assert_eq!(ignore_online.availability_state(&at(20, 0)), AvailabilityState::OutsideWindow);
+        let always_on = Client { ignore_availability: true, ..ignore_online.clone() };
+        assert_eq!(always_on.availability_state(&at(20, 0)), AvailabilityState::Available);
+        assert_eq!(always_on.time_until_available(&at(20, 0)), None);
+        let ignore_availability = Client { ignore_availability: true, ..offline.clone() };
+        assert_eq!(ignore_availability.availability_state(&at(20, 0)), AvailabilityState::Offline);
+
+        let blackout = Client {
+            blackout_start: Some((noon - chrono::Duration::hours(1)).into()),
+            ..available.clone()
+        };
+        assert_eq!(blackout.availability_state(&noon), AvailabilityState::Blackout);
+        let draining = Client { draining: true, ..available };
+        assert_eq!(draining.availability_state(&noon), AvailabilityState::Draining);
+
+        // precedence: draining > blackout > offline > outside window
+        let everything = Client { draining: true, ..blackout.clone() };
+        assert_eq!(everything.availability_state(&at(20, 0)), AvailabilityState::Draining);
+        let offline_blackout = Client { online: false, ..blackout };
+        assert_eq!(offline_blackout.availability_state(&at(20, 0)), AvailabilityState::Blackout);
+        assert_eq!(offline.availability_state(&at(20, 0)), AvailabilityState::Offline);
+    }
+
+    #[test]
+    fn test_parse_time_of_day() {
+        assert_eq!(parse_time_of_day("08:30").unwrap(), NaiveTime::from_hms_opt(8, 30, 0).unwrap());
+        assert_eq!(parse_time_of_day(" 23:59:59 ").unwrap(), NaiveTime::from_hms_opt(23, 59, 59).unwrap());
+        assert!(parse_time_of_day("25:00").is_err());
+        assert!(parse_time_of_day("8 am").is_err());
+    }
+
+    #[test]
+    fn test_availability_window() {
+        let daytime = client("08:00", "18:00");
+        assert!(daytime.within_availability_window(&at(8, 0)));
+        assert!(daytime.within_availability_window(&at(17, 59)));
+        assert!(!daytime.within_availability_window(&at(18, 0)));
+        assert!(!daytime.within_availability_window(&at(3, 0)));
+
+        let overnight = client("22:00", "06:00");
+        assert!(overnight.within_availability_window(&at(23, 0)));
+        assert!(overnight.within_availability_window(&at(5, 59)));
+        assert!(!overnight.within_availability_window(&at(6, 0)));
+        assert!(!overnight.within_availability_window(&at(12, 0)));
+
+        assert!(client("", "").within_availability_window(&at(12, 0)));
+        assert!(client("00:00", "00:00").within_availability_window(&at(12, 0)));
+        let malformed = client("08:00", "");
+        assert!(malformed.availability_window().is_err());
+        assert!(malformed.within_availability_window(&at(3, 0)));
+    }
+
+    #[test]
+    fn test_availability_cron() {
+        // 2021-05-01 is a Saturday
+        let weekdays = Client { availability_cron: Some("* * 8-17 * * Mon-Fri".to_string()), ..client("00:00", "01:00") };
+        assert!(!weekdays.within_availability_window(&at(12, 0)));
+        let monday = Utc.with_ymd_and_hms(2021, 5, 3, 12, 0, 0).unwrap();
+        assert!(weekdays.within_availability_window(&monday));
+        assert!(!weekdays.within_availability_window(&(monday + chrono::Duration::hours(6))));
+
+        let malformed = Client { availability_cron: Some("weekdays".to_string()), ..client("", "") };
+        assert!(malformed.within_availability_window(&monday));
+        assert_eq!(validate_client_availability(&[malformed]).len(), 1);
+    }
+
+    #[test]
+    fn test_time_until_available() {
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        let daytime = client("08:00", "18:00");
+        assert_eq!(daytime.time_until_available(&at(12, 0)), None);
+        assert_eq!(daytime.time_until_available(&at(6, 0)), Some(hours(2)));
+        // already passed today, opens tomorrow
+        assert_eq!(daytime.time_until_available(&at(20, 0)), Some(hours(12)));
+        assert_eq!(daytime.time_until_available(&at(18, 30)), Some(hours(13) + Duration::from_secs(1800)));
+
+        let overnight = client("22:00", "06:00");
+        assert_eq!(overnight.time_until_available(&at(23, 0)), None);
+        assert_eq!(overnight.time_until_available(&at(6, 0)), Some(hours(16)));
+
+        let weekdays = Client { availability_cron: Some("* * 8-17 * * Mon-Fri".to_string()), ..client("", "") };
+        // Saturday noon until Monday 8 am
+        assert_eq!(weekdays.time_until_available(&at(12, 0)), Some(hours(44)));
+        assert_eq!(client("", "").time_until_available(&at(3, 0)), None);
+    }
+
+    #[test]
+    fn test_available_during() {
+        let daytime = client("08:00", "18:00");
+        assert!(daytime.available_during(&at(9, 0), &at(17, 0)));
+        assert!(daytime.available_during(&at(8, 0), &at(18, 0)));
+        // partial overlaps
+        assert!(!daytime.available_during(&at(7, 0), &at(9, 0)));
+        assert!(!daytime.available_during(&at(17, 0), &at(19, 0)));
+        assert!(!daytime.available_during(&at(9, 0), &(at(9, 0) + chrono::Duration::days(1))));
+
+        let overnight = client("22:00", "06:00");
+        assert!(overnight.available_during(&at(23, 0), &(at(5, 0) + chrono::Duration::days(1))));
+        assert!(overnight.available_during(&at(2, 0), &at(3, 0)));
+        assert!(!overnight.available_during(&at(23, 0), &(at(7, 0) + chrono::Duration::days(1))));
+        assert!(!overnight.available_during(&at(21, 0), &at(23, 0)));
+
+        let always = client("", "");
+        assert!(always.available_during(&at(0, 0), &(at(0, 0) + chrono::Duration::days(3))));
+
+        // 2021-05-01 is a Saturday, 2021-05-03 a Monday
+        let weekdays = Client { availability_cron: Some("* * 8-17 * * Mon-Fri".to_string()), ..client("", "") };
+        let monday = |hour| Utc.with_ymd_and_hms(2021, 5, 3, hour, 0, 0).unwrap();
+        assert!(weekdays.available_during(&monday(9), &monday(10)));
+        assert!(weekdays.available_during(&monday(9), &monday(18)));
+        assert!(!weekdays.available_during(&monday(17), &monday(19)));
+        assert!(!weekdays.available_during(&monday(9), &(monday(9) + chrono::Duration::days(400))));
+
+        // long ranges are checked in blocks instead of second by second
+        let every_second = Client { availability_cron: Some("* * * * * *".to_string()), ..client("", "") };
+        assert!(every_second.available_during(&monday(0), &(monday(0) + chrono::Duration::days(3650))));
+        let may = Client { availability_cron: Some("* * * * May *".to_string()), ..client("", "") };
+        assert!(may.available_during(&monday(9), &Utc.with_ymd_and_hms(2021, 6, 1, 0, 0, 0).unwrap()));
+        assert!(!may.available_during(&monday(9), &Utc.with_ymd_and_hms(2021, 6, 1, 0, 0, 1).unwrap()));
+        let half_minutes = Client { availability_cron: Some("0-29 * 9 * * *".to_string()), ..client("", "") };
+        assert!(half_minutes.available_during(&monday(9), &(monday(9) + chrono::Duration::seconds(30))));
+        assert!(!half_minutes.available_during(&monday(9), &(monday(9) + chrono::Duration::seconds(31))));
+    }
+
+    #[test]
+    fn test_next_available_client() {
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        let morning = Client { name: "morning".to_string(), online: true, ..client("08:00", "12:00") };
+        let evening = Client { name: "evening".to_string(), online: true, ..client("18:00", "22:00") };
+        let offline = Client { name: "offline".to_string(), ..client("", "") };
+        let clients = vec![morning.clone(), evening.clone(), offline];
+        let (next, until) = next_available_client(&clients, &at(14, 0)).unwrap();
+        assert_eq!((next.name.as_str(), until), ("evening", hours(4)));
+        let (next, until) = next_available_client(&clients, &at(9, 0)).unwrap();
+        assert_eq!((next.name.as_str(), until), ("morning", Duration::ZERO));
+
+        // the blackout ends at 16:00, the window opens at 18:00
+        let blackout = Client { blackout_end: Some(at(16, 0).into()), ..evening };
+        assert_eq!(next_available_client(std::slice::from_ref(&blackout), &at(14, 0)).unwrap().1, hours(4));
+        let offline_blackout = Client { online: false, ..blackout.clone() };
+        assert!(next_available_client(&[offline_blackout], &at(14, 0)).is_none());
+        // 14:00 at UTC+2, the blackout ends at 16:00 local time and the window opens at 18:00 local time
+        let local = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        let local_blackout = Client { blackout_end: Some(at(14, 0).into()), ..blackout };
+        assert_eq!(next_available_client(&[local_blackout], &at(12, 0).with_timezone(&local)).unwrap().1, hours(4));
+        let endless = Client { blackout_start: Some(at(0, 0).into()), ..morning };
+        assert!(next_available_client(&[endless], &at(14, 0)).is_none());
+    }
+
+    #[test]
+    fn test_validate_client_availability() {
+        let override_at = |start: &str| CapacityOverride { start: start.to_string(), end: "06:00".to_string(), maximum_jobs: 2 };
+        let clients = vec![
+            client("08:00", "18:00"),
+            client("", ""),
+            client("8 am", "18:00"),
+            client("08:00", ""),
+            Client { capacity_schedule: vec![override_at("22:00"), override_at("late")], ..client("", "") },
+        ];
+        let invalid = validate_client_availability(&clients);
+        assert_eq!(invalid.len(), 3);
+        assert_eq!(invalid[0].1, "invalid time of day \"8 am\", expected HH:MM");
+        assert_eq!(invalid[1].1, "availability window of client client needs both a start and an end");
+        assert_eq!(invalid[2].0.capacity_schedule.len(), 2);
+        assert_eq!(invalid[2].1, "invalid time of day \"late\", expected HH:MM");
+    }
+
+    #[test]
+    fn test_maximum_jobs_at() {
+        let night = CapacityOverride { start: "22:00".to_string(), end: "06:00".to_string(), maximum_jobs: 4 };
+        let broken = CapacityOverride { start: "noon".to_string(), end: "13:00".to_string(), maximum_jobs: 9 };
+        let client = Client { maximum_jobs: 1, capacity_schedule: vec![broken, night], ..client("", "") };
+        assert_eq!(client.maximum_jobs_at(&at(21, 59)), 1);
+        assert_eq!(client.maximum_jobs_at(&at(22, 0)), 4);
+        assert_eq!(client.maximum_jobs_at(&at(5, 59)), 4);
+        assert_eq!(client.maximum_jobs_at(&at(6, 0)), 1);
+        assert_eq!(client.maximum_jobs_at(&at(12, 30)), 1);
+    }
+}
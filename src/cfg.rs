@@ -0,0 +1,206 @@
+use crate::{AssignedClient, Client, InfuserError};
+use chrono::{DateTime, FixedOffset, Local, Offset, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub db_url: String,
+    pub db_name: String,
+    /// database holding the clients collection, falls back to `db_name` if empty
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub clients_db: String,
+    /// database holding the jobs collection, falls back to `db_name` if empty
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub jobs_db: String,
+    /// whether `db::ensure_indexes` also creates a unique index on the client `Name`
+    #[serde(skip_serializing_if = "crate::is_false")]
+    pub unique_client_names: bool,
+    /// IANA name of the timezone scheduling decisions are made in, e.g. `Europe/Berlin`,
+    /// empty for the local timezone of the system, see `Config::now`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub timezone: String,
+}
+
+/// A `Config` as parsed, before defaults are applied
+///
+/// `None` means the field was absent, while an empty string was set to empty on purpose and
+/// overrides an earlier value when merging.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub(crate) struct PartialConfig {
+    db_url: Option<String>,
+    db_name: Option<String>,
+    clients_db: Option<String>,
+    jobs_db: Option<String>,
+    unique_client_names: Option<bool>,
+    timezone: Option<String>,
+}
+
+impl PartialConfig {
+    /// Fields set in `other` replace the ones of `self`
+    pub(crate) fn merge(self, other: PartialConfig) -> PartialConfig {
+        PartialConfig {
+            db_url: other.db_url.or(self.db_url),
+            db_name: other.db_name.or(self.db_name),
+            clients_db: other.clients_db.or(self.clients_db),
+            jobs_db: other.jobs_db.or(self.jobs_db),
+            unique_client_names: other.unique_client_names.or(self.unique_client_names),
+            timezone: other.timezone.or(self.timezone),
+        }
+    }
+
+    /// Fills every absent field with the default of `Config`
+    pub(crate) fn resolve(self) -> Config {
+        Config {
+            db_url: self.db_url.unwrap_or_default(),
+            db_name: self.db_name.unwrap_or_default(),
+            clients_db: self.clients_db.unwrap_or_default(),
+            jobs_db: self.jobs_db.unwrap_or_default(),
+            unique_client_names: self.unique_client_names.unwrap_or_default(),
+            timezone: self.timezone.unwrap_or_default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads JSON config files in order, fields of later files override the ones of earlier files
+    ///
+    /// Only fields that are present in a file override, so a local file only needs to contain what it
+    /// changes. Setting a field to `""` clears it. Fields no file sets keep their default.
+    pub fn read_merged<P: AsRef<Path>>(paths: &[P]) -> Result<Config, Box<dyn Error>> {
+        let mut merged = PartialConfig::default();
+        for path in paths {
+            let partial: PartialConfig = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            merged = merged.merge(partial);
+        }
+        Ok(merged.resolve())
+    }
+
+    /// The database to read clients from
+    pub fn clients_db(&self) -> &str {
+        self.effective_clients_db(None)
+    }
+
+    /// The database to read and write jobs
+    pub fn jobs_db(&self) -> &str {
+        self.effective_jobs_db(None)
+    }
+
+    /// Resolves the database holding the jobs collection
+    ///
+    /// Precedence, first non-empty wins:
+    /// 1. `override_db`, e.g. a scratch database in tests
+    /// 2. `jobs_db`
+    /// 3. `db_name`
+    ///
+    /// The functions in `db` take the resolved name, pass the result of this instead of a field of `Config`.
+    /// The client a job references is looked up in the `$db` of its DBRef if set, see `db::resolve_assigned_client`.
+    pub fn effective_jobs_db<'a>(&'a self, override_db: Option<&'a str>) -> &'a str {
+        first_non_empty(&[override_db.unwrap_or_default(), &self.jobs_db, &self.db_name])
+    }
+
+    /// Resolves the database holding the clients collection, same precedence as `effective_jobs_db`
+    pub fn effective_clients_db<'a>(&'a self, override_db: Option<&'a str>) -> &'a str {
+        first_non_empty(&[override_db.unwrap_or_default(), &self.clients_db, &self.db_name])
+    }
+
+    /// Parses `timezone`, `None` means the local timezone of the system
+    pub fn timezone(&self) -> Result<Option<Tz>, InfuserError> {
+        match self.timezone.trim() {
+            "" => Ok(None),
+            name => name
+                .parse()
+                .map(Some)
+                .map_err(|e: String| InfuserError::new(&format!("invalid timezone {:?}: {}", name, e))),
+        }
+    }
+
+    /// The current time in the configured timezone
+    ///
+    /// Pass this as `now` to the selection functions: availability windows, blackouts and capacity
+    /// schedules are compared against the wall clock time of `now`, so `08:00` means 8 am in the
+    /// configured timezone no matter where the scheduler runs. Without a timezone, the system's local
+    /// time is used, which makes schedulers in different regions disagree about a client's window.
+    pub fn now(&self) -> Result<DateTime<FixedOffset>, InfuserError> {
+        Ok(match self.timezone()? {
+            Some(tz) => {
+                let now = Utc::now().with_timezone(&tz);
+                now.with_timezone(&now.offset().fix())
+            }
+            None => Local::now().into(),
+        })
+    }
+
+    /// Builds the DBRef a job uses to reference `client`
+    ///
+    /// `$db` is always set to the clients database, see `clients_db`.
+    /// Fails if the client has no id, see `Client::to_dbref`.
+    pub fn assigned_client(&self, client: &Client) -> Result<AssignedClient, InfuserError> {
+        client.to_dbref(self.clients_db())
+    }
+}
+
+fn first_non_empty<'a>(candidates: &[&'a str]) -> &'a str {
+    candidates.iter().find(|db| !db.is_empty()).copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_fallback() {
+        let client = Client { id: Some(bson::oid::ObjectId::new()), ..Default::default() };
+        let mut cfg = Config { db_name: "avior".to_string(), ..Default::default() };
+        assert_eq!(cfg.clients_db(), "avior");
+        assert_eq!(cfg.jobs_db(), "avior");
+        assert_eq!(cfg.assigned_client(&client).unwrap().db, "avior");
+
+        cfg.clients_db = "fleet".to_string();
+        assert_eq!(cfg.clients_db(), "fleet");
+        assert_eq!(cfg.jobs_db(), "avior");
+        let dbref = cfg.assigned_client(&client).unwrap();
+        assert_eq!(dbref.db, "fleet");
+        assert_eq!(Some(dbref.id), client.id);
+    }
+
+    #[test]
+    fn test_timezone() {
+        let mut cfg = Config::default();
+        assert!(cfg.timezone().unwrap().is_none());
+        cfg.timezone = "Asia/Tokyo".to_string();
+        assert_eq!(cfg.timezone().unwrap(), Some(chrono_tz::Asia::Tokyo));
+        assert_eq!(cfg.now().unwrap().offset().local_minus_utc(), 9 * 3600);
+        cfg.timezone = "Mars/Olympus".to_string();
+        assert!(cfg.now().is_err());
+    }
+
+    #[test]
+    fn test_partial_config_merge() {
+        let base: PartialConfig = serde_json::from_str(r#"{"db_url": "mongodb://base", "db_name": "avior", "jobs_db": "queue"}"#).unwrap();
+        let local: PartialConfig = serde_json::from_str(r#"{"db_name": "scratch", "jobs_db": ""}"#).unwrap();
+        let cfg = base.merge(local).resolve();
+        assert_eq!(cfg.db_url, "mongodb://base");
+        assert_eq!(cfg.db_name, "scratch");
+        // explicitly emptied, falls back to db_name again
+        assert_eq!(cfg.jobs_db(), "scratch");
+        assert!(!cfg.unique_client_names);
+    }
+
+    #[test]
+    fn test_effective_db() {
+        let mut cfg = Config { db_name: "avior".to_string(), ..Default::default() };
+        assert_eq!(cfg.effective_jobs_db(None), "avior");
+        assert_eq!(cfg.effective_jobs_db(Some("")), "avior");
+        cfg.jobs_db = "queue".to_string();
+        assert_eq!(cfg.effective_jobs_db(None), "queue");
+        assert_eq!(cfg.effective_jobs_db(Some("scratch")), "scratch");
+        assert_eq!(cfg.effective_clients_db(None), "avior");
+    }
+}
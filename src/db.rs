@@ -1,11 +1,40 @@
+use crate::availability::validate_client_availability;
+use crate::cfg::Config;
+use crate::fleet::{self, JobMove};
 use crate::Client;
-use crate::Job;
+use crate::{InfuserError, InfuserErrorKind};
+use crate::worker::JobStore;
+use crate::{Job, JobJson, RoundRobinState};
+use chrono::Utc;
 use mongodb::{
-    bson::{self, doc, Bson},
-    error::Error as MongoError,
+    bson::{self, doc, oid::ObjectId, Bson, Document},
+    error::{Error as MongoError, ErrorKind, WriteFailure},
+    options::{
+        AggregateOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertOneOptions, ReplaceOptions, ReturnDocument,
+        UpdateOptions, WriteConcern,
+    },
     sync::Client as MongoClient,
 };
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fs,
+    path::Path,
+    error::Error,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// server error codes that indicate a transient condition (failover, shutdown, network timeouts)
+const RETRYABLE_CODES: [i32; 12] = [6, 7, 89, 91, 189, 262, 9001, 10107, 11600, 11602, 13435, 13436];
+/// server error code for a write violating a unique index
+const DUPLICATE_KEY: i32 = 11000;
+/// server error code for a collection or database that doesn't exist
+const NAMESPACE_NOT_FOUND: i32 = 26;
+/// `_id` of the scheduler's document in the `settings` collection
+const SCHEDULER_SETTINGS: &str = "scheduler";
+/// `_id` of the round robin rotation in the `settings` collection
+const ROUND_ROBIN_SETTINGS: &str = "round_robin";
 
 pub fn connect(uri: &str) -> Result<MongoClient, MongoError> {
     //let conn_url = format!("mongodb://{}/", cfg.db_url);
@@ -14,10 +43,19 @@ pub fn connect(uri: &str) -> Result<MongoClient, MongoError> {
     Ok(client)
 }
 
-pub fn get_clients(mongo_client: &MongoClient, db: &String) -> Result<Vec<Client>, MongoError> {
-    let db = mongo_client.database(&db);
+pub fn get_clients(mongo_client: &MongoClient, db: &str) -> Result<Vec<Client>, MongoError> {
+    find_clients(mongo_client, db, None)
+}
+
+/// Same as `get_clients`, but the server aborts the query after `max_time`
+pub(crate) fn get_clients_within(mongo_client: &MongoClient, db: &str, max_time: Duration) -> Result<Vec<Client>, MongoError> {
+    find_clients(mongo_client, db, FindOptions::builder().max_time(max_time).build())
+}
+
+fn find_clients(mongo_client: &MongoClient, db: &str, options: impl Into<Option<FindOptions>>) -> Result<Vec<Client>, MongoError> {
+    let db = mongo_client.database(db);
     let collection = db.collection("clients");
-    let cur = collection.find(doc! {}, None)?;
+    let cur = collection.find(doc! {}, options)?;
     let mut clients = Vec::new();
     for result in cur {
         match result {
@@ -31,9 +69,217 @@ pub fn get_clients(mongo_client: &MongoClient, db: &String) -> Result<Vec<Client
     Ok(clients)
 }
 
-pub fn get_jobs(mongo_client: &MongoClient, db: &String) -> Result<Vec<Job>, MongoError> {
+/// Writes a client to the clients collection and returns its id
+///
+/// A client without an id is inserted with a newly assigned one. A client with an id replaces the
+/// stored client with that id, or is inserted under it if it doesn't exist yet.
+/// The online state is written as well, so don't use this to update the configuration of a running client
+/// unless its `online` field is current.
+pub fn upsert_client(mongo_client: &MongoClient, db: &str, client: &Client) -> Result<ObjectId, MongoError> {
+    let id = match &client.id {
+        Some(id) => id.to_owned(),
+        None => ObjectId::new(),
+    };
+    let mut document = bson::to_document(client)?;
+    document.insert("_id", id.to_owned());
+    let options = ReplaceOptions::builder().upsert(true).build();
+    mongo_client
+        .database(db)
+        .collection("clients")
+        .replace_one(doc! { "_id": id.to_owned() }, document, options)?;
+    Ok(id)
+}
+
+/// The outcome of `bulk_load_clients`
+#[derive(Debug, Default, Clone)]
+pub struct LoadReport {
+    /// ids of the clients that were written
+    pub loaded: Vec<ObjectId>,
+    /// clients that were left out, with the reason
+    pub rejected: Vec<(Client, String)>,
+}
+
+/// Validates `clients` and writes the valid ones with `upsert_client`, e.g. to seed a new environment
+///
+/// A client is rejected if its availability can't be parsed, see `validate_client_availability`,
+/// if its `maximum_jobs` is negative, or if its name is already used by another client, either in
+/// `clients` or in the database. Of several clients sharing a new name, the first one is loaded.
+/// Rejected clients don't stop the load, they are reported instead.
+pub fn bulk_load_clients(mongo_client: &MongoClient, cfg: &Config, clients: Vec<Client>) -> Result<LoadReport, MongoError> {
+    let existing = get_clients(mongo_client, cfg.clients_db())?;
+    let (valid, rejected) = validate_seed_clients(clients, &existing);
+    let mut report = LoadReport { rejected, ..Default::default() };
+    for client in valid {
+        report.loaded.push(upsert_client(mongo_client, cfg.clients_db(), &client)?);
+    }
+    Ok(report)
+}
+
+fn validate_seed_clients(clients: Vec<Client>, existing: &[Client]) -> (Vec<Client>, Vec<(Client, String)>) {
+    let mut taken: HashMap<String, Option<ObjectId>> = existing.iter().map(|c| (c.name.to_owned(), c.id.to_owned())).collect();
+    let mut valid = Vec::new();
+    let mut rejected = Vec::new();
+    for client in clients {
+        let availability = validate_client_availability(std::slice::from_ref(&client));
+        let error = if let Some((_, error)) = availability.into_iter().next() {
+            Some(error)
+        } else if client.maximum_jobs < 0 {
+            Some(format!("maximum jobs of client {} must not be negative", client.name))
+        } else {
+            match taken.get(&client.name) {
+                Some(id) if id.is_none() || *id != client.id => Some(format!("client name {} is already in use", client.name)),
+                _ => None,
+            }
+        };
+        match error {
+            Some(error) => rejected.push((client, error)),
+            None => {
+                taken.insert(client.name.to_owned(), client.id.to_owned());
+                valid.push(client);
+            }
+        }
+    }
+    (valid, rejected)
+}
+
+/// Lists client names used by more than one client, sorted
+///
+/// Clients without an id are matched by name, see `Client`'s `PartialEq`,
+/// so duplicate names make the ignore list hit the wrong client.
+pub fn find_duplicate_client_names(mongo_client: &MongoClient, db: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let pipeline = vec![
+        doc! { "$match": { "Name": { "$type": "string" } } },
+        doc! { "$group": { "_id": "$Name", "count": { "$sum": 1 } } },
+        doc! { "$match": { "count": { "$gt": 1 } } },
+        doc! { "$sort": { "_id": 1 } },
+    ];
+    let mut names = Vec::new();
+    for result in mongo_client.database(db).collection("clients").aggregate(pipeline, None)? {
+        names.push(result?.get_str("_id")?.to_owned());
+    }
+    Ok(names)
+}
+
+/// All pools clients are assigned to, sorted and without duplicates
+///
+/// Uses the server side `distinct`, so the clients themselves aren't transferred. Clients without
+/// a pool are not reported as an empty pool.
+pub fn distinct_pools(mongo_client: &MongoClient, db: &str) -> Result<Vec<String>, MongoError> {
+    let values = mongo_client.database(db).collection("clients").distinct("Pool", None, None)?;
+    let mut pools: Vec<String> = values
+        .into_iter()
+        .filter_map(|value| match value {
+            Bson::String(pool) if !pool.is_empty() => Some(pool),
+            _ => None,
+        })
+        .collect();
+    pools.sort();
+    pools.dedup();
+    Ok(pools)
+}
+
+/// Creates a unique index on the client `Name`, so duplicate names are rejected on insert
+///
+/// Fails if the collection already contains duplicates, resolve those first using `find_duplicate_client_names`.
+pub fn ensure_unique_client_name_index(mongo_client: &MongoClient, db: &str) -> Result<(), MongoError> {
+    let command = doc! {
+        "createIndexes": "clients",
+        "indexes": [{ "key": { "Name": 1 }, "name": "Name_unique", "unique": true }],
+    };
+    mongo_client.database(db).run_command(command, None)?;
+    Ok(())
+}
+
+/// Records a heartbeat of a client by setting its `LastSeen` date to the current time
+///
+/// Returns whether a client with the given id was found
+pub fn record_heartbeat(mongo_client: &MongoClient, db: &str, client_id: &ObjectId) -> Result<bool, MongoError> {
+    let filter = doc! { "_id": client_id.to_owned() };
+    let update = doc! { "$set": { "LastSeen": Utc::now() } };
+    let result = mongo_client.database(db).collection("clients").update_one(filter, update, None)?;
+    Ok(result.matched_count > 0)
+}
+
+/// Sets the `Online` field of a single client, see `sync_online_status` for many clients
+///
+/// Returns whether a client with the given id was found
+pub fn set_client_online(mongo_client: &MongoClient, db: &str, client_id: &ObjectId, online: bool) -> Result<bool, MongoError> {
+    let filter = doc! { "_id": client_id.to_owned() };
+    let update = doc! { "$set": { "Online": online } };
+    let result = mongo_client.database(db).collection("clients").update_one(filter, update, None)?;
+    Ok(result.matched_count > 0)
+}
+
+/// The outcome of `sync_online_status`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    /// how many clients actually changed their online state
+    pub modified: i32,
+    /// clients whose update failed, with the server's error message
+    pub failed: Vec<(ObjectId, String)>,
+}
+
+/// Updates the `Online` field of many clients in a single round trip
+///
+/// Sends one `update` command containing a statement per client, unordered, so a failing statement
+/// doesn't stop the others. Clients missing from the database are skipped. Failed statements are
+/// reported per client next to the number of modified clients, the other updates are applied.
+pub fn sync_online_status(mongo_client: &MongoClient, db: &str, statuses: &HashMap<ObjectId, bool>) -> Result<SyncReport, Box<dyn Error>> {
+    if statuses.is_empty() {
+        return Ok(SyncReport::default());
+    }
+    let (ids, updates): (Vec<&ObjectId>, Vec<Bson>) = statuses
+        .iter()
+        .map(|(id, online)| {
+            let update = doc! {
+                "q": { "_id": id.to_owned() },
+                "u": { "$set": { "Online": *online } },
+            };
+            (id, Bson::Document(update))
+        })
+        .unzip();
+    let command = doc! { "update": "clients", "updates": updates, "ordered": false };
+    let result = mongo_client.database(db).run_command(command, None)?;
+    sync_report(&result, &ids)
+}
+
+/// Reads the reply of the `update` command sent by `sync_online_status`, `ids` in statement order
+fn sync_report(result: &Document, ids: &[&ObjectId]) -> Result<SyncReport, Box<dyn Error>> {
+    let mut report = SyncReport { modified: get_count(result, "nModified")?, ..Default::default() };
+    if let Ok(errors) = result.get_array("writeErrors") {
+        for error in errors {
+            let error = error
+                .as_document()
+                .ok_or_else(|| InfuserError::new(&format!("write error is not a document: {:?}", error)))?;
+            let id = usize::try_from(get_count(error, "index")?)
+                .ok()
+                .and_then(|index| ids.get(index))
+                .ok_or_else(|| InfuserError::new(&format!("write error for an unknown statement: {}", error)))?;
+            let message = error.get_str("errmsg").unwrap_or("unknown error");
+            report.failed.push(((*id).to_owned(), message.to_owned()));
+        }
+    }
+    Ok(report)
+}
+
+/// Fetches the client a job is assigned to by following its DBRef
+///
+/// The DBRef's `$ref` collection and `$db` database are honored, `db` is used if the reference
+/// doesn't name a database. Returns `None` if the referenced client doesn't exist (anymore).
+/// Whether the client is online is left to the caller to check.
+pub fn resolve_assigned_client(mongo_client: &MongoClient, db: &str, job: &Job) -> Result<Option<Client>, MongoError> {
+    let dbref = &job.assigned_client;
+    let db = if dbref.db.is_empty() { db } else { &dbref.db };
+    let filter = doc! { "_id": dbref.id.to_owned() };
+    match mongo_client.database(db).collection(&dbref.collection).find_one(filter, None)? {
+        Some(doc) => Ok(Some(bson::from_bson(Bson::Document(doc))?)),
+        None => Ok(None),
+    }
+}
+
+pub fn get_jobs(mongo_client: &MongoClient, db: &str) -> Result<Vec<Job>, MongoError> {
     let mut jobs = Vec::new();
-    for result in mongo_client.database(&db).collection("jobs").find(doc! {}, None)? {
+    for result in mongo_client.database(db).collection("jobs").find(doc! {}, None)? {
         match result {
             Ok(doc) => {
                 let job: Job = bson::from_bson(Bson::Document(doc))?;
@@ -45,36 +291,697 @@ pub fn get_jobs(mongo_client: &MongoClient, db: &String) -> Result<Vec<Job>, Mon
     Ok(jobs)
 }
 
-pub fn job_exists(mongo_client: &MongoClient, db: &String, job_pathstring: &str) -> Result<bool, MongoError> {
+/// The fields `get_jobs_sorted` can sort by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JobSortField {
+    /// insertion order, ids are generated in ascending order
+    #[default]
+    Id,
+    Name,
+    Subtitle,
+}
+
+impl JobSortField {
+    fn field_name(self) -> &'static str {
+        match self {
+            JobSortField::Id => "_id",
+            JobSortField::Name => "Name",
+            JobSortField::Subtitle => "Subtitle",
+        }
+    }
+}
+
+/// Same as `get_jobs`, but sorted by `field`
+///
+/// `JobSortField::Id` ascending sorts by insertion order, which is what `get_jobs` roughly returns.
+pub fn get_jobs_sorted(mongo_client: &MongoClient, db: &str, field: JobSortField, ascending: bool) -> Result<Vec<Job>, MongoError> {
+    let direction = if ascending { 1 } else { -1 };
+    let options = FindOptions::builder().sort(doc! { field.field_name(): direction }).build();
+    let mut jobs = Vec::new();
+    for result in mongo_client.database(db).collection("jobs").find(doc! {}, options)? {
+        jobs.push(bson::from_document(result?)?);
+    }
+    Ok(jobs)
+}
+
+/// Matches jobs that are neither running nor completed
+fn queued_jobs_filter() -> Document {
+    doc! { "Status": { "$ne": "Running" }, "CompletedAt": { "$exists": false } }
+}
+
+/// A page of jobs and the number of jobs across all pages
+#[derive(Debug, Clone, Default)]
+pub struct JobPage {
+    pub jobs: Vec<Job>,
+    pub total: i64,
+}
+
+/// Reads one page of the queued jobs assigned to `client_id`, sorted by `sort`
+///
+/// `skip` jobs are skipped and at most `limit` are returned, `total` counts every queued job of the client
+/// so callers can render pagination. Without a stable sort a job may appear on two pages or none
+/// when jobs are added in between, `JobSortField::Id` is stable.
+pub fn client_queue(
+    mongo_client: &MongoClient,
+    db: &str,
+    client_id: &ObjectId,
+    skip: i64,
+    limit: i64,
+    sort: JobSortField,
+    ascending: bool,
+) -> Result<JobPage, MongoError> {
+    let jobs = mongo_client.database(db).collection("jobs");
+    let mut filter = queued_jobs_filter();
+    filter.insert("AssignedClient.$id", client_id.to_owned());
+    let total = jobs.count_documents(filter.clone(), None)?;
+    let direction = if ascending { 1 } else { -1 };
+    let options = FindOptions::builder()
+        .sort(doc! { sort.field_name(): direction })
+        .skip(skip)
+        .limit(limit)
+        .build();
+    let mut page = JobPage { jobs: Vec::new(), total };
+    for result in jobs.find(filter, options)? {
+        page.jobs.push(bson::from_document(result?)?);
+    }
+    Ok(page)
+}
+
+/// Reads all queued jobs, optionally only those assigned to `client_id`,
+/// and serializes them as a JSON array of `JobJson` for the Go consumer
+pub fn jobs_feed_json(mongo_client: &MongoClient, cfg: &Config, client_id: Option<&ObjectId>) -> Result<String, Box<dyn Error>> {
+    let mut filter = queued_jobs_filter();
+    if let Some(id) = client_id {
+        filter.insert("AssignedClient.$id", id.to_owned());
+    }
+    let mut feed = Vec::new();
+    for result in mongo_client.database(cfg.jobs_db()).collection("jobs").find(filter, None)? {
+        let job: Job = bson::from_document(result?)?;
+        feed.push(JobJson::from(job));
+    }
+    Ok(serde_json::to_string_pretty(&feed)?)
+}
+
+/// Fetches the queued jobs and buckets them by the id of the client they are assigned to
+///
+/// Clients without queued jobs don't appear in the map.
+pub fn jobs_by_client(mongo_client: &MongoClient, db: &str) -> Result<HashMap<ObjectId, Vec<Job>>, MongoError> {
+    let mut jobs_by_client: HashMap<ObjectId, Vec<Job>> = HashMap::new();
+    for result in mongo_client.database(db).collection("jobs").find(queued_jobs_filter(), None)? {
+        let job: Job = bson::from_document(result?)?;
+        jobs_by_client.entry(job.assigned_client.id.to_owned()).or_default().push(job);
+    }
+    Ok(jobs_by_client)
+}
+
+/// The anti affinity groups of the unfinished jobs per assigned client id, queued and running jobs alike,
+/// see `get_eligible_client_with_anti_affinity`
+pub fn anti_affinity_groups(mongo_client: &MongoClient, db: &str) -> Result<HashMap<ObjectId, HashSet<String>>, MongoError> {
+    let filter = doc! { "CompletedAt": { "$exists": false }, "AntiAffinityGroup": { "$type": "string" } };
+    let mut groups: HashMap<ObjectId, HashSet<String>> = HashMap::new();
+    for result in mongo_client.database(db).collection("jobs").find(filter, None)? {
+        let job: Job = bson::from_document(result?)?;
+        if let Some(group) = job.anti_affinity_group {
+            groups.entry(job.assigned_client.id).or_default().insert(group);
+        }
+    }
+    Ok(groups)
+}
+
+pub fn job_exists(mongo_client: &MongoClient, db: &str, job_pathstring: &str) -> Result<bool, MongoError> {
+    find_job_by_path(mongo_client, db, job_pathstring, None)
+}
+
+/// Same as `job_exists`, but the server aborts the query after `max_time`
+pub(crate) fn job_exists_within(mongo_client: &MongoClient, db: &str, job_pathstring: &str, max_time: Duration) -> Result<bool, MongoError> {
+    find_job_by_path(mongo_client, db, job_pathstring, FindOneOptions::builder().max_time(max_time).build())
+}
+
+fn find_job_by_path(mongo_client: &MongoClient, db: &str, job_pathstring: &str, options: impl Into<Option<FindOneOptions>>) -> Result<bool, MongoError> {
     let filter = doc! { "Path": { "$eq" : job_pathstring } };
-    let result = mongo_client.database(&db).collection("jobs").find_one(filter, None)?;
-    match result {
-        Some(_) => {
-            return Ok(true);
+    let result = mongo_client.database(db).collection("jobs").find_one(filter, options)?;
+    Ok(result.is_some())
+}
+
+/// Returns which of `paths` already belong to a job, using a single query
+///
+/// Paths are compared exactly like `job_exists` does, without any normalization.
+pub fn jobs_exist(mongo_client: &MongoClient, db: &str, paths: &[String]) -> Result<HashSet<String>, MongoError> {
+    if paths.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let filter = doc! { "Path": { "$in": paths } };
+    let options = FindOptions::builder().projection(doc! { "_id": 0, "Path": 1 }).build();
+    let mut existing = HashSet::new();
+    for result in mongo_client.database(db).collection("jobs").find(filter, options)? {
+        if let Ok(path) = result?.get_str("Path") {
+            existing.insert(path.to_owned());
         }
-        None => {}
     }
-    Ok(false)
+    Ok(existing)
 }
 
 /// Inserts a job into the database and returns the insert id if successful
 pub fn insert_job(
     mongo_client: &MongoClient,
-    db: &String,
+    db: &str,
     job: &Job,
 ) -> Result<String, MongoError> {
-    let serialized = bson::to_bson(&job)?;
-    let document = serialized.as_document().unwrap();
+    insert_job_with(mongo_client, db, job, None)
+}
+
+/// Same as `insert_job`, but gives up waiting for the write to be acknowledged after `w_timeout`
+///
+/// A timed out insert may still have been written, the server doesn't roll it back.
+pub(crate) fn insert_job_within(mongo_client: &MongoClient, db: &str, job: &Job, w_timeout: Duration) -> Result<String, MongoError> {
+    let write_concern = WriteConcern::builder().w_timeout(w_timeout).build();
+    insert_job_with(mongo_client, db, job, InsertOneOptions::builder().write_concern(write_concern).build())
+}
+
+fn insert_job_with(mongo_client: &MongoClient, db: &str, job: &Job, options: impl Into<Option<InsertOneOptions>>) -> Result<String, MongoError> {
+    let document = job_document(job)?;
     let result = mongo_client
-        .database(&db)
+        .database(db)
         .collection("jobs")
-        .insert_one(document.to_owned(), None)?;
+        .insert_one(document, options)?;
     let insert_id = result.inserted_id.to_string();
     Ok(insert_id)
 }
 
-pub fn get_machine_jobcount(mongo_client: &MongoClient, db: &String) -> Result<HashMap<String, i32>, Box<dyn Error>> {
-    let query = vec![
+/// The outcome of `force_assign_job`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForcedAssignment {
+    pub insert_id: String,
+    /// names the job, the client and the checks that were skipped, for the caller to show or log
+    pub warning: String,
+}
+
+/// Inserts a job for the client with the given id, bypassing client selection
+///
+/// Meant for manual overrides: capacity, online state, availability and priorities are ignored
+/// and not even the existence of the client is checked, the job may go to a full or offline client.
+/// Unlike `scheduler::schedule_once` the insert isn't deduplicated by path either.
+/// The job references the client like every other assignment, see `Config::assigned_client`.
+pub fn force_assign_job(mongo_client: &MongoClient, cfg: &Config, client_id: &ObjectId, mut job: Job) -> Result<ForcedAssignment, Box<dyn Error>> {
+    let client = Client { id: Some(client_id.to_owned()), ..Default::default() };
+    job.assigned_client = cfg.assigned_client(&client)?;
+    let insert_id = insert_job(mongo_client, cfg.jobs_db(), &job)?;
+    let warning = format!(
+        "force assigned job {} to client {}, capacity and online state were not checked",
+        job.path, client_id
+    );
+    Ok(ForcedAssignment { insert_id, warning })
+}
+
+/// The outcome of validating a job with `validate_job`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobValidation {
+    /// the job would be inserted
+    WouldInsert,
+    /// a job with the same path already exists and the insert would be skipped
+    Duplicate,
+}
+
+/// Validates a job that is going to be assigned to `client` without writing anything
+///
+/// Performs the same serialization as `insert_job` and the path check of `insert_job_if_absent`.
+/// Fails if the job can't be serialized or the client has no id a job could reference.
+pub fn validate_job(mongo_client: &MongoClient, db: &str, client: &Client, job: &Job) -> Result<JobValidation, Box<dyn Error>> {
+    if client.id.is_none() {
+        return Err(Box::new(InfuserError::new(&format!(
+            "client {} has no id, jobs can't be assigned to it",
+            client.name
+        ))));
+    }
+    job_document(job)?;
+    if job_exists(mongo_client, db, &job.path)? {
+        return Ok(JobValidation::Duplicate);
+    }
+    Ok(JobValidation::WouldInsert)
+}
+
+/// Inserts a job unless a job with the same path already exists
+///
+/// Returns the insert id if the job was inserted, `None` if it was already present
+pub fn insert_job_if_absent(mongo_client: &MongoClient, db: &str, job: &Job) -> Result<Option<String>, MongoError> {
+    if job_exists(mongo_client, db, &job.path)? {
+        return Ok(None);
+    }
+    insert_job(mongo_client, db, job).map(Some)
+}
+
+/// The outcome of `insert_job_idempotent`, holding the id of the job stored under the key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotentInsert {
+    /// no job had the key, the job was inserted
+    Inserted(String),
+    /// a job with the same key already existed and was left untouched
+    Existing(String),
+}
+
+/// Inserts a job unless a job with the same `idempotency_key` already exists
+///
+/// The insert is a single upsert keyed on `IdempotencyKey`, so a producer that retries after a restart
+/// gets the job it inserted before instead of a duplicate, even if the path changed in between.
+/// Concurrent inserts with the same key are only deduplicated with a unique index on `IdempotencyKey`,
+/// see `ensure_indexes`; without it both upserts may insert. Fails if the job has no key.
+pub fn insert_job_idempotent(mongo_client: &MongoClient, db: &str, job: &Job) -> Result<IdempotentInsert, Box<dyn Error>> {
+    let key = match &job.idempotency_key {
+        Some(key) => key.to_owned(),
+        None => return Err(Box::new(InfuserError::new(&format!("job {} has no idempotency key", job.path)))),
+    };
+    let jobs = mongo_client.database(db).collection("jobs");
+    let filter = doc! { "IdempotencyKey": key.as_str() };
+    let update = doc! { "$setOnInsert": job_document(job)? };
+    let options = UpdateOptions::builder().upsert(true).build();
+    match jobs.update_one(filter.clone(), update, options) {
+        Ok(result) => {
+            if let Some(id) = result.upserted_id {
+                return Ok(IdempotentInsert::Inserted(id.to_string()));
+            }
+        }
+        // lost the race against a concurrent upsert with the same key
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::WriteError(WriteFailure::WriteError(w)) if w.code == DUPLICATE_KEY) => (),
+        Err(e) => return Err(e.into()),
+    }
+    match jobs.find_one(filter, None)? {
+        Some(existing) => Ok(IdempotentInsert::Existing(existing.get("_id").map(Bson::to_string).unwrap_or_default())),
+        None => Err(Box::new(InfuserError::new(&format!("job with idempotency key {:?} vanished during insert", key)))),
+    }
+}
+
+/// Inserts a job, retrying on transient errors
+///
+/// A failed write may still have been applied by the server, so every attempt goes through
+/// `insert_job_if_absent`: a retry after a write that actually succeeded finds the job by its path
+/// and returns `None` instead of inserting a duplicate. This only holds as long as job paths are
+/// unique, which means the retry is not safe for callers that insert several jobs with the same path.
+///
+/// ### Parameters:
+/// - attempts: the maximum number of attempts, including the first one
+/// - backoff: the delay before the first retry, doubled after each further failure
+///
+/// Errors that are not retryable are returned immediately.
+pub fn insert_job_retry(
+    mongo_client: &MongoClient,
+    db: &str,
+    job: &Job,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<Option<String>, MongoError> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match insert_job_if_absent(mongo_client, db, job) {
+            Err(e) if attempt < attempts && is_retryable(&e) => {
+                eprintln!("retrying insert of job {} after error in db::insert_job_retry: {:?}", job.path, e);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn job_document(job: &Job) -> Result<Document, MongoError> {
+    Ok(bson::to_document(job)?)
+}
+
+/// Checks whether an error is transient and the operation can safely be attempted again
+pub fn is_retryable(error: &MongoError) -> bool {
+    if error.contains_label("RetryableWriteError") {
+        return true;
+    }
+    match error.kind.as_ref() {
+        ErrorKind::Io(_) | ErrorKind::ConnectionPoolClearedError { .. } | ErrorKind::ServerSelectionError { .. } => true,
+        ErrorKind::CommandError(e) => RETRYABLE_CODES.contains(&e.code),
+        _ => false,
+    }
+}
+
+/// Marks a job as finished by setting its `CompletedAt` date to the current time
+///
+/// Returns whether a job with the given id was found
+pub fn mark_job_completed(mongo_client: &MongoClient, db: &str, job_id: &ObjectId) -> Result<bool, MongoError> {
+    let filter = doc! { "_id": job_id.to_owned() };
+    let update = doc! { "$set": { "CompletedAt": Utc::now() } };
+    let result = mongo_client.database(db).collection("jobs").update_one(filter, update, None)?;
+    Ok(result.matched_count > 0)
+}
+
+/// Claims the oldest queued job assigned to `client_id` by setting its status to `Running`
+/// and its `StartedAt` date to the current time
+///
+/// The job is claimed atomically, two workers polling for the same client never receive the same job.
+/// Returns `None` if the client has no queued jobs
+pub fn claim_next_job(mongo_client: &MongoClient, db: &str, client_id: &ObjectId) -> Result<Option<Job>, Box<dyn Error>> {
+    let mut filter = queued_jobs_filter();
+    filter.insert("AssignedClient.$id", client_id.to_owned());
+    let update = doc! { "$set": { "Status": "Running", "StartedAt": Utc::now() } };
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "_id": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+    match mongo_client.database(db).collection("jobs").find_one_and_update(filter, update, options)? {
+        Some(document) => Ok(Some(bson::from_document(document)?)),
+        None => Ok(None),
+    }
+}
+
+/// Sets `fields` on a job, but only if its stored version still is `expected_version`
+///
+/// This is a compare-and-swap: the update matches on id and version and increments the version,
+/// so of several workers that read the same version only the first one succeeds and all others get
+/// a `Conflict` error. On a conflict, read the job again and retry with its new version.
+/// Jobs stored before versioning count as version 0. Returns the new version
+pub fn update_job_versioned(
+    mongo_client: &MongoClient,
+    db: &str,
+    job_id: &ObjectId,
+    expected_version: i64,
+    fields: Document,
+) -> Result<i64, Box<dyn Error>> {
+    let mut filter = doc! { "_id": job_id.to_owned() };
+    if expected_version == 0 {
+        filter.insert("$or", vec![doc! { "Version": 0_i64 }, doc! { "Version": { "$exists": false } }]);
+    } else {
+        filter.insert("Version", expected_version);
+    }
+    let update = doc! { "$set": fields, "$inc": { "Version": 1_i64 } };
+    let result = mongo_client.database(db).collection("jobs").update_one(filter, update, None)?;
+    if result.matched_count == 0 {
+        return Err(Box::new(InfuserError::with_kind(
+            InfuserErrorKind::Conflict,
+            &format!("job {} was changed or removed since version {}", job_id, expected_version),
+        )));
+    }
+    Ok(expected_version + 1)
+}
+
+/// Claims a job read at `job.version` by setting its status to `Running`, see `update_job_versioned`
+///
+/// An alternative to `claim_next_job` for workers that pick their job themselves.
+pub fn claim_job_versioned(mongo_client: &MongoClient, db: &str, job: &Job) -> Result<i64, Box<dyn Error>> {
+    let job_id = match &job.id {
+        Some(id) => id,
+        None => return Err(Box::new(InfuserError::new(&format!("job {} has no id", job.path)))),
+    };
+    update_job_versioned(mongo_client, db, job_id, job.version, doc! { "Status": "Running", "StartedAt": Utc::now() })
+}
+
+/// Claims jobs from the jobs collection of a Mongo database, see `claim_next_job`
+pub struct MongoJobStore<'a> {
+    pub mongo_client: &'a MongoClient,
+    pub db: String,
+}
+
+impl JobStore for MongoJobStore<'_> {
+    fn claim_next_job(&self, client_id: &ObjectId) -> Result<Option<Job>, Box<dyn Error>> {
+        claim_next_job(self.mongo_client, &self.db, client_id)
+    }
+}
+
+/// Applies the moves planned by `fleet::plan_rebalance` by reassigning each job to its new client
+///
+/// Only queued jobs are moved, a job that was started, completed or reassigned since the plan
+/// was made is matched by neither `queued_jobs_filter` nor client and left untouched.
+/// Returns the number of jobs that were moved
+pub fn apply_job_moves(mongo_client: &MongoClient, db: &str, moves: &[JobMove]) -> Result<u64, MongoError> {
+    let jobs = mongo_client.database(db).collection("jobs");
+    let mut moved = 0;
+    for job_move in moves {
+        let update = doc! { "$set": { "AssignedClient.$id": job_move.to.to_owned() } };
+        moved += jobs.update_one(job_move_filter(job_move), update, None)?.modified_count as u64;
+    }
+    Ok(moved)
+}
+
+/// Matches the job of `job_move` while it is still queued on the client it moves from
+fn job_move_filter(job_move: &JobMove) -> Document {
+    let mut filter = queued_jobs_filter();
+    filter.insert("_id", job_move.job_id.to_owned());
+    filter.insert("AssignedClient.$id", job_move.from.to_owned());
+    filter
+}
+
+/// Moves a job from `jobs` to the `jobs_archive` collection of the same database
+///
+/// The driver doesn't support transactions, so the job is first copied into the archive and then
+/// deleted from `jobs`. If the delete fails the job exists in both collections, calling this again
+/// overwrites the archived copy and completes the move. Fails if the job doesn't exist in `jobs`.
+pub fn archive_job(mongo_client: &MongoClient, db: &str, job_id: &ObjectId) -> Result<(), Box<dyn Error>> {
+    let db = mongo_client.database(db);
+    let jobs = db.collection("jobs");
+    let filter = doc! { "_id": job_id.to_owned() };
+    let job = match jobs.find_one(filter.clone(), None)? {
+        Some(job) => job,
+        None => return Err(Box::new(InfuserError::with_kind(InfuserErrorKind::NotFound, &format!("job {} not found", job_id)))),
+    };
+    let options = ReplaceOptions::builder().upsert(true).build();
+    db.collection("jobs_archive").replace_one(filter.clone(), job, options)?;
+    jobs.delete_one(filter, None)?;
+    Ok(())
+}
+
+/// Asks the worker processing a job to stop it
+///
+/// Workers are expected to poll `is_cancel_requested` and stop gracefully.
+/// Returns whether a job with the given id was found
+pub fn request_cancel(mongo_client: &MongoClient, db: &str, job_id: &ObjectId) -> Result<bool, MongoError> {
+    let filter = doc! { "_id": job_id.to_owned() };
+    let update = doc! { "$set": { "CancelRequested": true } };
+    let result = mongo_client.database(db).collection("jobs").update_one(filter, update, None)?;
+    Ok(result.matched_count > 0)
+}
+
+/// Checks whether cancelling a job has been requested, a job that doesn't exist is not cancelled
+pub fn is_cancel_requested(mongo_client: &MongoClient, db: &str, job_id: &ObjectId) -> Result<bool, MongoError> {
+    let filter = doc! { "_id": job_id.to_owned(), "CancelRequested": true };
+    let result = mongo_client.database(db).collection("jobs").find_one(filter, None)?;
+    Ok(result.is_some())
+}
+
+/// Pauses or resumes job assignment of every scheduler using the database
+///
+/// The switch lives in the `settings` collection, in the document with the `_id` `scheduler`,
+/// as its `Paused` field. While paused, `scheduler::schedule_once` fails with `Paused` instead of
+/// assigning jobs. Unlike draining a client this stops all assignment, jobs already assigned keep running.
+/// `schedule_once` reads the switch from the jobs database, so pass `cfg.jobs_db()`.
+pub fn set_scheduler_paused(mongo_client: &MongoClient, db: &str, paused: bool) -> Result<(), MongoError> {
+    let options = UpdateOptions::builder().upsert(true).build();
+    mongo_client.database(db).collection("settings").update_one(
+        doc! { "_id": SCHEDULER_SETTINGS },
+        doc! { "$set": { "Paused": paused } },
+        options,
+    )?;
+    Ok(())
+}
+
+/// Whether job assignment is paused, see `set_scheduler_paused`; never having set the switch means not paused
+pub fn is_scheduler_paused(mongo_client: &MongoClient, db: &str) -> Result<bool, MongoError> {
+    find_paused_switch(mongo_client, db, None)
+}
+
+/// Same as `is_scheduler_paused`, but the server aborts the query after `max_time`
+pub(crate) fn is_scheduler_paused_within(mongo_client: &MongoClient, db: &str, max_time: Duration) -> Result<bool, MongoError> {
+    find_paused_switch(mongo_client, db, FindOneOptions::builder().max_time(max_time).build())
+}
+
+fn find_paused_switch(mongo_client: &MongoClient, db: &str, options: impl Into<Option<FindOneOptions>>) -> Result<bool, MongoError> {
+    let filter = doc! { "_id": SCHEDULER_SETTINGS, "Paused": true };
+    let result = mongo_client.database(db).collection("settings").find_one(filter, options)?;
+    Ok(result.is_some())
+}
+
+/// Stores the rotation of `get_round_robin_client`, so it survives a restart of the scheduler
+///
+/// The state is kept in the `settings` collection, see `set_scheduler_paused`, as the document with
+/// the `_id` `round_robin`: `{ _id: "round_robin", Cursors: [{ Priority: <i32>, LastClient: <client id> }] }`.
+/// Schedulers sharing a database share the rotation.
+pub fn save_scheduler_state(mongo_client: &MongoClient, db: &str, state: &RoundRobinState) -> Result<(), MongoError> {
+    let mut document = bson::to_document(state)?;
+    document.insert("_id", ROUND_ROBIN_SETTINGS);
+    let options = ReplaceOptions::builder().upsert(true).build();
+    mongo_client
+        .database(db)
+        .collection("settings")
+        .replace_one(doc! { "_id": ROUND_ROBIN_SETTINGS }, document, options)?;
+    Ok(())
+}
+
+/// Loads the rotation stored by `save_scheduler_state`, a fresh rotation if none was stored yet
+pub fn load_scheduler_state(mongo_client: &MongoClient, db: &str) -> Result<RoundRobinState, MongoError> {
+    let filter = doc! { "_id": ROUND_ROBIN_SETTINGS };
+    match mongo_client.database(db).collection("settings").find_one(filter, None)? {
+        Some(document) => Ok(bson::from_document(document)?),
+        None => Ok(RoundRobinState::default()),
+    }
+}
+
+/// Creates a TTL index so Mongo deletes jobs once `field` is older than `expire_after_secs`
+///
+/// TTL indexes only expire documents whose indexed field holds a BSON date,
+/// documents without the field or with any other type (e.g. a date string) are kept forever.
+/// `CompletedAt` as set by `mark_job_completed` is a real date.
+/// Calling this again with the same settings is a no-op, changing `expire_after_secs` for an
+/// existing index fails with an index options conflict and requires dropping the index first.
+pub fn ensure_jobs_ttl_index(mongo_client: &MongoClient, db: &str, field: &str, expire_after_secs: i64) -> Result<(), MongoError> {
+    let command = doc! {
+        "createIndexes": "jobs",
+        "indexes": [{
+            "key": { field: 1 },
+            "name": format!("{}_ttl", field),
+            "expireAfterSeconds": expire_after_secs,
+        }],
+    };
+    mongo_client.database(db).run_command(command, None)?;
+    Ok(())
+}
+
+/// Which indexes `ensure_indexes` created and which already existed, by index name
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IndexReport {
+    pub created: Vec<String>,
+    pub present: Vec<String>,
+}
+
+/// Creates the indexes the scheduler relies on, unless they already exist
+///
+/// - `Path_unique`: unique job `Path`, the duplicate check of `insert_job_if_absent`
+/// - `AssignedClient_id`: job `AssignedClient.$id`, used to count and claim jobs per client
+/// - `IdempotencyKey_unique`: unique job `IdempotencyKey` for `insert_job_idempotent`,
+///   only jobs that have a key are indexed
+/// - `Name_unique`: unique client `Name`, only if `cfg.unique_client_names` is set,
+///   see `ensure_unique_client_name_index`
+///
+/// Indexes are matched by name, an existing index with the same name but different keys is reported
+/// as present and left alone. Creating a unique index fails if the collection already holds duplicates.
+pub fn ensure_indexes(mongo_client: &MongoClient, cfg: &Config) -> Result<IndexReport, MongoError> {
+    let mut report = IndexReport::default();
+    let jobs_db = mongo_client.database(cfg.jobs_db());
+    ensure_index(&jobs_db, "jobs", "Path_unique", doc! { "Path": 1 }, doc! { "unique": true }, &mut report)?;
+    ensure_index(&jobs_db, "jobs", "AssignedClient_id", doc! { "AssignedClient.$id": 1 }, doc! {}, &mut report)?;
+    // jobs without a key don't take part, otherwise they would all collide on the missing key
+    let partial = doc! { "unique": true, "partialFilterExpression": { "IdempotencyKey": { "$exists": true } } };
+    ensure_index(&jobs_db, "jobs", "IdempotencyKey_unique", doc! { "IdempotencyKey": 1 }, partial, &mut report)?;
+    if cfg.unique_client_names {
+        let clients_db = mongo_client.database(cfg.clients_db());
+        ensure_index(&clients_db, "clients", "Name_unique", doc! { "Name": 1 }, doc! { "unique": true }, &mut report)?;
+    }
+    Ok(report)
+}
+
+fn ensure_index(
+    db: &mongodb::sync::Database,
+    collection: &str,
+    name: &str,
+    key: Document,
+    options: Document,
+    report: &mut IndexReport,
+) -> Result<(), MongoError> {
+    if index_names(db, collection)?.iter().any(|existing| existing == name) {
+        report.present.push(name.to_owned());
+        return Ok(());
+    }
+    let mut index = doc! { "key": key, "name": name };
+    index.extend(options);
+    let command = doc! { "createIndexes": collection, "indexes": [index] };
+    db.run_command(command, None)?;
+    report.created.push(name.to_owned());
+    Ok(())
+}
+
+/// Lists the names of the indexes of a collection, a collection that doesn't exist yet has none
+fn index_names(db: &mongodb::sync::Database, collection: &str) -> Result<Vec<String>, MongoError> {
+    let response = match db.run_command(doc! { "listIndexes": collection }, None) {
+        Ok(response) => response,
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::CommandError(c) if c.code == NAMESPACE_NOT_FOUND) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let batch = response.get_document("cursor").and_then(|cursor| cursor.get_array("firstBatch"));
+    Ok(batch
+        .map(|indexes| {
+            indexes
+                .iter()
+                .filter_map(|index| index.as_document()?.get_str("name").ok().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+pub fn get_machine_jobcount(mongo_client: &MongoClient, db: &str) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+    get_machine_jobcount_with_options(mongo_client, db, false)
+}
+
+/// Counts the jobs per assigned client, see `get_machine_jobcount`
+///
+/// ### Parameters:
+/// - allow_disk_use: lets the server spill the `$group` stage to temporary files.
+///   Aggregation stages are limited to 100 MB of memory, so enable this once the jobs collection
+///   is large enough for the aggregation to fail with a memory limit error.
+///   Spilling to disk is slower, so leave it disabled for regular sized collections.
+pub fn get_machine_jobcount_with_options(
+    mongo_client: &MongoClient,
+    db: &str,
+    allow_disk_use: bool,
+) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+    let options = AggregateOptions::builder().allow_disk_use(allow_disk_use).build();
+    aggregate_per_client(mongo_client, db, Bson::Int32(1), options)
+}
+
+/// Same as `get_machine_jobcount`, but the server aborts the aggregation after `max_time`
+pub(crate) fn get_machine_jobcount_within(mongo_client: &MongoClient, db: &str, max_time: Duration) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+    let options = AggregateOptions::builder().max_time(max_time).build();
+    aggregate_per_client(mongo_client, db, Bson::Int32(1), options)
+}
+
+/// Sums up the `EstimatedMinutes` of the unfinished jobs per assigned client
+///
+/// Running and queued jobs both count, like they do for `get_machine_jobcount`: a queued job is
+/// already bound to its client and will take up its minutes there. Completed jobs are left out.
+/// Jobs without an estimate count as zero minutes.
+/// Returns the load in minutes per client id, like `get_machine_jobcount` does for job counts
+pub fn get_machine_load_minutes(mongo_client: &MongoClient, db: &str) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+    let minutes = Bson::Document(doc! { "$ifNull": ["$EstimatedMinutes", 0] });
+    aggregate_per_client(mongo_client, db, minutes, AggregateOptions::default())
+}
+
+/// Groups the unfinished jobs by the id of their assigned client and sums up `sum` per group
+///
+/// Completed jobs stay in the collection until the TTL index removes them, see `mark_job_completed`,
+/// they don't count toward the load of their client.
+fn aggregate_per_client(
+    mongo_client: &MongoClient,
+    db: &str,
+    sum: Bson,
+    options: AggregateOptions,
+) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+    let query = per_client_pipeline(sum);
+    let cur = mongo_client.database(db).collection("jobs").aggregate(query, options)?;
+    let mut job_counts = HashMap::new();
+    for res in cur {
+        let doc = res?;
+        match client_group(&doc)? {
+            Some((client_id, count)) => {
+                job_counts.insert(client_id, count);
+            }
+            None => eprintln!("skipping jobs without an assigned client id in db::aggregate_per_client: {}", doc),
+        }
+    }
+    Ok(job_counts)
+}
+
+/// The pipeline behind `aggregate_per_client`
+fn per_client_pipeline(sum: Bson) -> Vec<Document> {
+    vec![
+        doc! {
+           "$match":{
+              "CompletedAt":{
+                 "$exists":false
+              }
+           }
+        },
         doc! {
            "$addFields":{
               "AssignedClient":{
@@ -98,22 +1005,542 @@ pub fn get_machine_jobcount(mongo_client: &MongoClient, db: &String) -> Result<H
                  "AssignedClient":"$AssignedClient"
               },
               "count":{
-                 "$sum":1
+                 "$sum":sum
               }
            }
         },
-    ];
-    let mut cur = mongo_client.database(&db).collection("jobs").aggregate(query, None)?;
-    let mut job_counts = HashMap::new();
-    while let Some(res) = cur.next() {
-        let doc = res?;
-        let count = doc.get_i32("count")?;
-        let oid_bson = doc
-            .get_document("_id")?
-            .get("AssignedClient")
-            .expect("Error aggregating jobs: AssignedClients are required to have an id");
-        let oid: bson::oid::ObjectId = bson::from_bson(oid_bson.to_owned())?;
-        job_counts.insert(oid.to_string(), count);
+    ]
+}
+
+/// Reads a number the server may have returned as int32, int64 or a whole double, e.g. a `$sum`
+fn get_count(doc: &Document, key: &str) -> Result<i32, InfuserError> {
+    let out_of_range = || InfuserError::new(&format!("{} of {} doesn't fit into an i32", key, doc));
+    match doc.get(key) {
+        Some(Bson::Int32(value)) => Ok(*value),
+        Some(Bson::Int64(value)) => i32::try_from(*value).map_err(|_| out_of_range()),
+        Some(Bson::Double(value)) if value.fract() == 0.0 => {
+            if *value >= i32::MIN as f64 && *value <= i32::MAX as f64 {
+                Ok(*value as i32)
+            } else {
+                Err(out_of_range())
+            }
+        }
+        Some(other) => Err(InfuserError::new(&format!("{} is not a whole number but {:?}", key, other))),
+        None => Err(InfuserError::with_kind(InfuserErrorKind::NotFound, &format!("{} is missing in {}", key, doc))),
+    }
+}
+
+/// Reads a group produced by `aggregate_per_client`
+///
+/// Returns `None` for the group of jobs whose `AssignedClient` has no object id, e.g. a null or
+/// malformed reference, so a single bad job doesn't fail the counts of the whole fleet.
+fn client_group(doc: &Document) -> Result<Option<(String, i32)>, Box<dyn Error>> {
+    let count = get_count(doc, "count")?;
+    match doc.get_document("_id")?.get("AssignedClient") {
+        Some(Bson::ObjectId(oid)) => Ok(Some((oid.to_string(), count))),
+        _ => Ok(None),
+    }
+}
+
+/// A job whose `AssignedClient` can't be trusted, see `verify_job_references`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceIssue {
+    pub job_id: Option<ObjectId>,
+    pub path: String,
+    pub problem: ReferenceProblem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceProblem {
+    /// no client with the referenced id exists
+    DanglingClient(ObjectId),
+    /// the DBRef points at a collection other than `clients`
+    WrongCollection(String),
+}
+
+/// Checks that every job references an existing client in the `clients` collection
+///
+/// Jobs whose DBRef names a `$db` are checked against the clients of that database, all others
+/// against `cfg.clients_db()`. Nothing is modified, fixing the reported jobs is up to the caller.
+pub fn verify_job_references(mongo_client: &MongoClient, cfg: &Config) -> Result<Vec<ReferenceIssue>, MongoError> {
+    let jobs = get_jobs(mongo_client, cfg.jobs_db())?;
+    let client_ids = referenced_client_ids(mongo_client, cfg, &jobs)?;
+    Ok(reference_issues(&jobs, &client_ids, cfg.clients_db()))
+}
+
+/// Why `get_unassigned_jobs` reported a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unassigned {
+    /// the DBRef id is the all zero ObjectId, a placeholder that never referenced a client
+    ZeroId,
+    /// the referenced client doesn't exist (anymore)
+    MissingClient,
+}
+
+/// Finds the jobs without a usable client, e.g. for a janitor that reassigns or deletes them
+///
+/// Clients are looked up like `verify_job_references` does. Unlike there, the collection of the DBRef
+/// isn't checked, only whether a client with its id exists.
+pub fn get_unassigned_jobs(mongo_client: &MongoClient, cfg: &Config) -> Result<Vec<(Job, Unassigned)>, MongoError> {
+    let jobs = get_jobs(mongo_client, cfg.jobs_db())?;
+    let client_ids = referenced_client_ids(mongo_client, cfg, &jobs)?;
+    Ok(unassigned_jobs(jobs, &client_ids, cfg.clients_db()))
+}
+
+fn unassigned_jobs(jobs: Vec<Job>, client_ids: &HashMap<String, HashSet<ObjectId>>, default_db: &str) -> Vec<(Job, Unassigned)> {
+    let zero = ObjectId::with_bytes([0; 12]);
+    jobs.into_iter()
+        .filter_map(|job| {
+            let dbref = &job.assigned_client;
+            let db = if dbref.db.is_empty() { default_db } else { &dbref.db };
+            if dbref.id == zero {
+                Some((job, Unassigned::ZeroId))
+            } else if client_ids.get(db).is_some_and(|ids| ids.contains(&dbref.id)) {
+                None
+            } else {
+                Some((job, Unassigned::MissingClient))
+            }
+        })
+        .collect()
+}
+
+/// The ids of all clients in every database the DBRefs of `jobs` point at
+fn referenced_client_ids(mongo_client: &MongoClient, cfg: &Config, jobs: &[Job]) -> Result<HashMap<String, HashSet<ObjectId>>, MongoError> {
+    let mut client_ids: HashMap<String, HashSet<ObjectId>> = HashMap::new();
+    for job in jobs {
+        let db = if job.assigned_client.db.is_empty() { cfg.clients_db() } else { &job.assigned_client.db };
+        if client_ids.contains_key(db) {
+            continue;
+        }
+        let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+        let mut ids = HashSet::new();
+        for result in mongo_client.database(db).collection("clients").find(doc! {}, options)? {
+            if let Ok(id) = result?.get_object_id("_id") {
+                ids.insert(id.to_owned());
+            }
+        }
+        client_ids.insert(db.to_owned(), ids);
+    }
+    Ok(client_ids)
+}
+
+/// Finds the reference issues of `jobs` given the client ids per database
+fn reference_issues(jobs: &[Job], client_ids: &HashMap<String, HashSet<ObjectId>>, default_db: &str) -> Vec<ReferenceIssue> {
+    let mut issues = Vec::new();
+    for job in jobs {
+        let dbref = &job.assigned_client;
+        let problem = if dbref.collection != "clients" {
+            ReferenceProblem::WrongCollection(dbref.collection.to_owned())
+        } else {
+            let db = if dbref.db.is_empty() { default_db } else { &dbref.db };
+            if client_ids.get(db).is_some_and(|ids| ids.contains(&dbref.id)) {
+                continue;
+            }
+            ReferenceProblem::DanglingClient(dbref.id.to_owned())
+        };
+        issues.push(ReferenceIssue { job_id: job.id.to_owned(), path: job.path.to_owned(), problem });
+    }
+    issues
+}
+
+/// Finds groups of jobs that point at the same file, oldest job first, see `dedup_jobs`
+///
+/// Paths are normalized before comparing: surrounding whitespace is trimmed, backslashes count as
+/// slashes, trailing slashes are dropped and case is ignored. With `match_name_and_subtitle`, jobs
+/// also need the same name and subtitle to count as duplicates.
+/// Jobs without an id and completed jobs are left out, a completed job only waits for its TTL
+/// and must not make a queued job for the same file look like a duplicate. Ids are compared to
+/// find the oldest job, since they start with their creation time.
+pub fn find_duplicate_jobs(mongo_client: &MongoClient, cfg: &Config, match_name_and_subtitle: bool) -> Result<Vec<Vec<Job>>, MongoError> {
+    Ok(duplicate_clusters(get_jobs(mongo_client, cfg.jobs_db())?, match_name_and_subtitle))
+}
+
+/// Deletes the duplicates found by `find_duplicate_jobs`, keeping the oldest job of every group
+///
+/// Running duplicates are kept as well, a worker is already processing them.
+/// Returns the deleted jobs
+pub fn dedup_jobs(mongo_client: &MongoClient, cfg: &Config, match_name_and_subtitle: bool) -> Result<Vec<Job>, MongoError> {
+    let removed: Vec<Job> = find_duplicate_jobs(mongo_client, cfg, match_name_and_subtitle)?
+        .into_iter()
+        .flat_map(|cluster| cluster.into_iter().skip(1).filter(|job| job.status.is_queued()))
+        .collect();
+    if removed.is_empty() {
+        return Ok(removed);
+    }
+    let ids: Vec<ObjectId> = removed.iter().filter_map(|job| job.id.to_owned()).collect();
+    mongo_client.database(cfg.jobs_db()).collection("jobs").delete_many(doc! { "_id": { "$in": ids } }, None)?;
+    Ok(removed)
+}
+
+fn duplicate_clusters(jobs: Vec<Job>, match_name_and_subtitle: bool) -> Vec<Vec<Job>> {
+    let mut clusters: HashMap<(String, String, String), Vec<Job>> = HashMap::new();
+    for job in jobs.into_iter().filter(|job| job.id.is_some() && job.completed_at.is_none()) {
+        let key = if match_name_and_subtitle {
+            (normalize_path(&job.path), job.name.to_owned(), job.subtitle.to_owned())
+        } else {
+            (normalize_path(&job.path), String::new(), String::new())
+        };
+        clusters.entry(key).or_default().push(job);
+    }
+    let mut duplicates: Vec<Vec<Job>> = clusters
+        .into_iter()
+        .filter(|(_, cluster)| cluster.len() > 1)
+        .map(|(_, mut cluster)| {
+            cluster.sort_by_key(|job| job.id.as_ref().map(|id| id.bytes()));
+            cluster
+        })
+        .collect();
+    duplicates.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+    duplicates
+}
+
+fn normalize_path(path: &str) -> String {
+    path.trim().replace('\\', "/").trim_end_matches('/').to_lowercase()
+}
+
+/// Writes every job to `path` as a JSON array of extended JSON documents
+///
+/// Ids, DBRefs and dates are kept as is, so `import_jobs` restores the exact documents.
+/// Returns the number of exported jobs
+pub fn export_jobs<P: AsRef<Path>>(mongo_client: &MongoClient, cfg: &Config, path: P) -> Result<usize, Box<dyn Error>> {
+    let mut documents = Vec::new();
+    for result in mongo_client.database(cfg.jobs_db()).collection("jobs").find(doc! {}, None)? {
+        documents.push(result?);
+    }
+    fs::write(path, documents_to_json(documents.clone())?)?;
+    Ok(documents.len())
+}
+
+/// Inserts the jobs of a file written by `export_jobs`
+///
+/// With `skip_existing_paths`, jobs whose path already exists are left out, see `jobs_exist`.
+/// Otherwise every job is inserted and jobs whose id already exists fail the import.
+/// Returns the number of imported jobs
+pub fn import_jobs<P: AsRef<Path>>(mongo_client: &MongoClient, cfg: &Config, path: P, skip_existing_paths: bool) -> Result<usize, Box<dyn Error>> {
+    let mut documents = documents_from_json(&fs::read_to_string(path)?)?;
+    if skip_existing_paths {
+        let paths: Vec<String> = documents.iter().filter_map(|doc| doc.get_str("Path").ok().map(str::to_owned)).collect();
+        let existing = jobs_exist(mongo_client, cfg.jobs_db(), &paths)?;
+        documents.retain(|doc| doc.get_str("Path").map_or(true, |path| !existing.contains(path)));
+    }
+    if documents.is_empty() {
+        return Ok(0);
+    }
+    let imported = documents.len();
+    mongo_client.database(cfg.jobs_db()).collection("jobs").insert_many(documents, None)?;
+    Ok(imported)
+}
+
+fn documents_to_json(documents: Vec<Document>) -> Result<String, serde_json::Error> {
+    let json: Vec<serde_json::Value> = documents.into_iter().map(|doc| Bson::Document(doc).into_relaxed_extjson()).collect();
+    serde_json::to_string_pretty(&json)
+}
+
+fn documents_from_json(json: &str) -> Result<Vec<Document>, Box<dyn Error>> {
+    let values: Vec<serde_json::Value> = serde_json::from_str(json)?;
+    let mut documents = Vec::new();
+    for value in values {
+        match Bson::try_from(value)? {
+            Bson::Document(doc) => documents.push(doc),
+            other => return Err(Box::new(InfuserError::new(&format!("expected a job document, found {}", other)))),
+        }
+    }
+    Ok(documents)
+}
+
+/// Exports the clients, their job counts and the resulting priority groups as a JSON snapshot
+///
+/// Meant to be attached to bug reports, see `fleet::ScheduleState` for the contents
+pub fn export_state(mongo_client: &MongoClient, cfg: &Config) -> Result<String, Box<dyn Error>> {
+    let clients = get_clients(mongo_client, cfg.clients_db())?;
+    let job_counts = get_machine_jobcount(mongo_client, cfg.jobs_db())?;
+    let state = fleet::schedule_state(clients, job_counts, &cfg.now()?);
+    Ok(serde_json::to_string_pretty(&state)?)
+}
+
+/// Caches the result of `get_machine_jobcount` and refreshes it once it is older than a ttl
+///
+/// Counts served from the cache don't see jobs inserted by other schedulers or finished since the
+/// last refresh. The longer the ttl, the more a client can be over-assigned beyond its maximum job count
+/// when several schedulers share a fleet, or left idle after its jobs finished.
+/// Jobs assigned by the owner of the cache can be accounted for right away with `increment`.
+pub struct JobCountsCache {
+    ttl: Duration,
+    fetched_at: Option<Instant>,
+    counts: HashMap<String, i32>,
+}
+
+impl JobCountsCache {
+    pub fn new(ttl: Duration) -> Self {
+        JobCountsCache {
+            ttl,
+            fetched_at: None,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Returns the job counts per client id, refreshing them from the database if they are stale
+    pub fn get(&mut self, mongo_client: &MongoClient, db: &str) -> Result<&HashMap<String, i32>, Box<dyn Error>> {
+        if self.is_stale() {
+            self.counts = get_machine_jobcount(mongo_client, db)?;
+            self.fetched_at = Some(Instant::now());
+        }
+        Ok(&self.counts)
+    }
+
+    /// Returns the job count of a single client, refreshing the counts if they are stale
+    pub fn for_client(&mut self, mongo_client: &MongoClient, db: &str, client_id: &str) -> Result<i32, Box<dyn Error>> {
+        Ok(self.get(mongo_client, db)?.get(client_id).copied().unwrap_or(0))
+    }
+
+    /// Whether the next access hits the database
+    pub fn is_stale(&self) -> bool {
+        self.fetched_at.is_none_or(|fetched_at| fetched_at.elapsed() >= self.ttl)
+    }
+
+    /// Counts a job that was just assigned to a client without waiting for the next refresh
+    pub fn increment(&mut self, client_id: &str) {
+        *self.counts.entry(client_id.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Forces a refresh on the next access
+    pub fn invalidate(&mut self) {
+        self.fetched_at = None;
+    }
+
+    /// Refreshes the counts right away and returns how far the cached counts were off, by client id
+    ///
+    /// A positive delta means the database has more jobs than the cache knew about, e.g. jobs inserted
+    /// outside this crate. Only clients whose count changed are reported, a client without jobs counts
+    /// as zero. The returned deltas are what drifted since the last refresh, including jobs that were
+    /// counted with `increment` but never made it into the database.
+    pub fn reconcile(&mut self, mongo_client: &MongoClient, db: &str) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+        let fresh = get_machine_jobcount(mongo_client, db)?;
+        let deltas = count_deltas(&self.counts, &fresh);
+        self.counts = fresh;
+        self.fetched_at = Some(Instant::now());
+        Ok(deltas)
+    }
+}
+
+fn count_deltas(cached: &HashMap<String, i32>, fresh: &HashMap<String, i32>) -> HashMap<String, i32> {
+    cached
+        .keys()
+        .chain(fresh.keys())
+        .filter_map(|client_id| {
+            let delta = fresh.get(client_id).copied().unwrap_or(0) - cached.get(client_id).copied().unwrap_or(0);
+            (delta != 0).then(|| (client_id.to_owned(), delta))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_documents_json_round_trip() -> Result<(), Box<dyn Error>> {
+        let job = Job {
+            id: Some(ObjectId::new()),
+            path: "C:\\rec\\a.ts".to_string(),
+            assigned_client: crate::AssignedClient { collection: "clients".to_string(), id: ObjectId::new(), db: "fleet".to_string() },
+            completed_at: Some(Utc::now().into()),
+            ..Default::default()
+        };
+        let document = job_document(&job)?;
+        let json = documents_to_json(vec![document.clone()])?;
+        let restored = documents_from_json(&json)?;
+        assert_eq!(restored.len(), 1);
+        let restored: Job = bson::from_document(restored[0].clone())?;
+        assert_eq!(restored.id, job.id);
+        assert_eq!(restored.path, job.path);
+        assert_eq!(restored.assigned_client.id, job.assigned_client.id);
+        assert_eq!(restored.assigned_client.db, "fleet");
+        assert_eq!(restored.completed_at.unwrap().0.timestamp_millis(), job.completed_at.unwrap().0.timestamp_millis());
+        assert!(documents_from_json("[1]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reference_issues() {
+        let known = ObjectId::new();
+        let dbref = |collection: &str, id: &ObjectId| crate::AssignedClient { collection: collection.to_string(), id: id.to_owned(), db: String::new() };
+        let job = |path: &str, assigned_client| Job { path: path.to_string(), assigned_client, ..Default::default() };
+        let missing = ObjectId::new();
+        let jobs = vec![
+            job("ok", dbref("clients", &known)),
+            job("dangling", dbref("clients", &missing)),
+            job("wrong", dbref("machines", &known)),
+        ];
+        let mut client_ids = HashMap::new();
+        client_ids.insert("avior".to_string(), vec![known].into_iter().collect());
+        let issues = reference_issues(&jobs, &client_ids, "avior");
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].path, "dangling");
+        assert_eq!(issues[0].problem, ReferenceProblem::DanglingClient(missing));
+        assert_eq!(issues[1].problem, ReferenceProblem::WrongCollection("machines".to_string()));
+    }
+
+    #[test]
+    fn test_validate_seed_clients() {
+        let client = |name: &str, maximum_jobs: i32| Client { id: Some(ObjectId::new()), name: name.to_string(), maximum_jobs, ..Default::default() };
+        let existing = vec![client("taken", 1)];
+        let update = Client { maximum_jobs: 4, ..existing[0].clone() };
+        let broken = Client { availability_start: "8 am".to_string(), availability_end: "18:00".to_string(), ..client("broken", 1) };
+        let (valid, rejected) = validate_seed_clients(
+            vec![client("new", 2), client("new", 2), client("taken", 1), update, broken, client("negative", -1)],
+            &existing,
+        );
+        let names: Vec<&str> = valid.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["new", "taken"]);
+        assert_eq!(valid[1].maximum_jobs, 4);
+        let names: Vec<&str> = rejected.iter().map(|(c, _)| c.name.as_str()).collect();
+        assert_eq!(names, vec!["new", "taken", "broken", "negative"]);
+    }
+
+    #[test]
+    fn test_count_deltas() {
+        let counts = |pairs: &[(&str, i32)]| -> HashMap<String, i32> { pairs.iter().map(|(id, count)| (id.to_string(), *count)).collect() };
+        let cached = counts(&[("same", 2), ("grown", 1), ("gone", 3)]);
+        let fresh = counts(&[("same", 2), ("grown", 4), ("new", 1)]);
+        assert_eq!(count_deltas(&cached, &fresh), counts(&[("grown", 3), ("gone", -3), ("new", 1)]));
+        assert!(count_deltas(&fresh, &fresh).is_empty());
+    }
+
+    #[test]
+    fn test_unassigned_jobs() {
+        let known = ObjectId::new();
+        let job = |name: &str, id: ObjectId| Job {
+            name: name.to_string(),
+            assigned_client: crate::AssignedClient { collection: "clients".to_string(), id, db: String::new() },
+            ..Default::default()
+        };
+        let jobs = vec![job("ok", known.to_owned()), job("zero", ObjectId::with_bytes([0; 12])), job("gone", ObjectId::new())];
+        let client_ids: HashMap<String, HashSet<ObjectId>> = vec![("avior".to_string(), vec![known].into_iter().collect())].into_iter().collect();
+        let unassigned: Vec<(String, Unassigned)> = unassigned_jobs(jobs, &client_ids, "avior").into_iter().map(|(job, reason)| (job.name, reason)).collect();
+        assert_eq!(unassigned, vec![("zero".to_string(), Unassigned::ZeroId), ("gone".to_string(), Unassigned::MissingClient)]);
+    }
+
+    #[test]
+    fn test_sync_report() -> Result<(), Box<dyn Error>> {
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        let result = doc! {
+            "n": 1,
+            "nModified": 1,
+            "writeErrors": [{ "index": 1, "code": 2, "errmsg": "bad update" }],
+            "ok": 1.0,
+        };
+        let report = sync_report(&result, &[&first, &second])?;
+        assert_eq!(report, SyncReport { modified: 1, failed: vec![(second.clone(), "bad update".to_owned())] });
+        let report = sync_report(&doc! { "n": 2, "nModified": 2, "ok": 1.0 }, &[&first, &second])?;
+        assert_eq!(report, SyncReport { modified: 2, failed: Vec::new() });
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_clusters() {
+        let job = |path: &str, name: &str| Job { id: Some(ObjectId::new()), path: path.to_string(), name: name.to_string(), ..Default::default() };
+        let oldest = job("D:\\Media\\Show.mkv", "show");
+        let jobs = vec![job("d:/media/show.mkv/", "renamed"), job("D:/Media/Other.mkv", "other"), Job { id: None, ..oldest.clone() }, oldest.clone()];
+        let clusters = duplicate_clusters(jobs.clone(), false);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].len(), 2);
+        assert_eq!(clusters[0][0].id, oldest.id);
+        assert!(duplicate_clusters(jobs, true).is_empty());
+
+        // the completed job only waits for its TTL, the queued one still has to be processed
+        let completed = Job { completed_at: Some(Utc::now().into()), ..job("D:/Media/Show.mkv", "show") };
+        let queued = job("D:/Media/Show.mkv", "show");
+        assert!(duplicate_clusters(vec![completed, queued], false).is_empty());
+    }
+
+    #[test]
+    fn test_client_group() -> Result<(), Box<dyn Error>> {
+        let oid = ObjectId::new();
+        let group = doc! { "_id": { "AssignedClient": oid.to_owned() }, "count": 3 };
+        assert_eq!(client_group(&group)?, Some((oid.to_string(), 3)));
+        let null_client = doc! { "_id": { "AssignedClient": Bson::Null }, "count": 1 };
+        assert_eq!(client_group(&null_client)?, None);
+        let missing_client = doc! { "_id": {}, "count": 1 };
+        assert_eq!(client_group(&missing_client)?, None);
+        let large = doc! { "_id": { "AssignedClient": oid.to_owned() }, "count": 7_i64 };
+        assert_eq!(client_group(&large)?, Some((oid.to_string(), 7)));
+        let double = doc! { "_id": { "AssignedClient": oid.to_owned() }, "count": 2.0 };
+        assert_eq!(client_group(&double)?, Some((oid.to_string(), 2)));
+        for invalid in [doc! { "count": i64::MAX }, doc! { "count": 1.5 }, doc! { "count": "3" }, doc! {}].iter() {
+            assert!(get_count(invalid, "count").is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_client_pipeline_skips_completed_jobs() {
+        let pipeline = per_client_pipeline(Bson::Int32(1));
+        assert_eq!(pipeline[0], doc! { "$match": { "CompletedAt": { "$exists": false } } });
+    }
+
+    #[test]
+    #[ignore = "requires a reachable mongodb instance"]
+    fn test_completed_jobs_are_not_counted() -> Result<(), Box<dyn Error>> {
+        let db = "avior_infuser_test_counts";
+        let mongo_client = connect("mongodb://192.168.178.75:27107")?;
+        let client = ObjectId::new();
+        let assigned_client = crate::AssignedClient { collection: "clients".to_string(), id: client.to_owned(), db: db.to_string() };
+        let queued = Job { path: "queued.ts".to_string(), assigned_client: assigned_client.clone(), ..Default::default() };
+        let completed_id = ObjectId::new();
+        let completed = Job { id: Some(completed_id.to_owned()), path: "completed.ts".to_string(), assigned_client, ..Default::default() };
+        insert_job(&mongo_client, db, &queued)?;
+        insert_job(&mongo_client, db, &completed)?;
+        mark_job_completed(&mongo_client, db, &completed_id)?;
+        let counts = get_machine_jobcount(&mongo_client, db);
+        mongo_client.database(db).drop(None)?;
+        assert_eq!(counts?.get(&client.to_string()), Some(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_move_filter_skips_started_and_completed_jobs() {
+        let job_move = JobMove { job_id: ObjectId::new(), from: ObjectId::new(), to: ObjectId::new() };
+        let filter = job_move_filter(&job_move);
+        assert_eq!(filter.get_document("Status").unwrap(), &doc! { "$ne": "Running" });
+        assert_eq!(filter.get_document("CompletedAt").unwrap(), &doc! { "$exists": false });
+        assert_eq!(filter.get_object_id("_id").unwrap(), &job_move.job_id);
+        assert_eq!(filter.get_object_id("AssignedClient.$id").unwrap(), &job_move.from);
+    }
+
+    #[test]
+    #[ignore = "requires a reachable mongodb instance"]
+    fn test_completed_jobs_are_not_moved() -> Result<(), Box<dyn Error>> {
+        let db = "avior_infuser_test_moves";
+        let mongo_client = connect("mongodb://192.168.178.75:27107")?;
+        let (from, to) = (ObjectId::new(), ObjectId::new());
+        let assigned_client = crate::AssignedClient { collection: "clients".to_string(), id: from.to_owned(), db: db.to_string() };
+        let job_id = ObjectId::new();
+        let completed = Job { id: Some(job_id.to_owned()), path: "completed.ts".to_string(), assigned_client, ..Default::default() };
+        insert_job(&mongo_client, db, &completed)?;
+        mark_job_completed(&mongo_client, db, &job_id)?;
+        let moved = apply_job_moves(&mongo_client, db, &[JobMove { job_id: job_id.to_owned(), from: from.to_owned(), to }]);
+        let job = mongo_client.database(db).collection("jobs").find_one(doc! { "_id": job_id }, None);
+        mongo_client.database(db).drop(None)?;
+        assert_eq!(moved?, 0);
+        assert_eq!(job?.unwrap().get_document("AssignedClient")?.get_object_id("$id")?, &from);
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_counts_cache_staleness() {
+        let mut cache = JobCountsCache::new(Duration::from_secs(60));
+        assert!(cache.is_stale());
+        cache.fetched_at = Some(Instant::now());
+        assert!(!cache.is_stale());
+        cache.increment("client");
+        cache.increment("client");
+        assert_eq!(cache.counts["client"], 2);
+        cache.invalidate();
+        assert!(cache.is_stale());
+
+        let mut no_caching = JobCountsCache::new(Duration::from_secs(0));
+        no_caching.fetched_at = Some(Instant::now());
+        assert!(no_caching.is_stale());
     }
-    Ok(job_counts)
 }
@@ -0,0 +1,946 @@
+use crate::availability::{next_available_client, validate_client_availability};
+use crate::{get_eligible_client_at, group_clients, is_eligible, is_selectable, remaining_capacity, Client, Job};
+use bson::oid::ObjectId;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+/// Describes how the clients in the database deviate from an expected configuration
+#[derive(Debug, Default, Clone)]
+pub struct FleetDiff {
+    /// clients that are in the database but not in the expected configuration
+    pub added: Vec<Client>,
+    /// clients that are expected but missing from the database
+    pub removed: Vec<Client>,
+    /// clients that exist on both sides but differ in at least one field
+    pub changed: Vec<ClientChange>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientChange {
+    pub name: String,
+    /// the differing fields, named like in the clients collection
+    pub fields: Vec<&'static str>,
+}
+
+impl FleetDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// A snapshot of everything the scheduler bases its decisions on
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct ScheduleState {
+    pub taken_at: String,
+    /// job counts per client id, as returned by `db::get_machine_jobcount`
+    pub job_counts: HashMap<String, i32>,
+    /// clients grouped by priority, in scheduling order
+    pub priority_groups: BTreeMap<i32, Vec<ClientState>>,
+    /// the client `get_eligible_client` would pick right now
+    pub next_client: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct ClientState {
+    pub client: Client,
+    pub current_jobs: Option<i32>,
+    pub eligible: bool,
+}
+
+/// Builds the scheduling snapshot for the given clients and job counts at `now`
+pub fn schedule_state<Tz: TimeZone>(clients: Vec<Client>, job_counts: HashMap<String, i32>, now: &DateTime<Tz>) -> ScheduleState
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let grouped = group_clients(clients, job_counts.to_owned());
+    let next_client = get_eligible_client_at(&grouped, &[], now).ok().map(|(client, _, _)| client.name.to_owned());
+    let priority_groups = grouped
+        .iter()
+        .map(|(prio, clients)| {
+            let mut states: Vec<ClientState> = clients
+                .iter()
+                .map(|(client, current_jobs)| ClientState {
+                    client: client.to_owned(),
+                    current_jobs: *current_jobs,
+                    eligible: is_eligible(client, *current_jobs, &HashSet::new(), now),
+                })
+                .collect();
+            states.sort_by(|a, b| a.client.name.cmp(&b.client.name));
+            (*prio, states)
+        })
+        .collect();
+    ScheduleState {
+        taken_at: now.to_rfc3339(),
+        job_counts,
+        priority_groups,
+        next_client,
+    }
+}
+
+/// Counts the clients per priority level
+///
+/// Returns `(eligible, total)` per priority, where eligible applies the same online, capacity
+/// and time rules as `get_eligible_client`.
+pub fn eligible_counts_by_priority<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> BTreeMap<i32, (usize, usize)> {
+    grouped_clients
+        .iter()
+        .map(|(prio, clients)| {
+            let eligible = clients
+                .iter()
+                .filter(|(client, current_jobs)| is_eligible(client, **current_jobs, &HashSet::new(), now))
+                .count();
+            (*prio, (eligible, clients.len()))
+        })
+        .collect()
+}
+
+/// Sums up the free slots of the available clients per priority group
+///
+/// Unavailable clients, e.g. offline or draining ones, and clients at or above their maximum
+/// contribute nothing, every priority group is part of the result even if it has no room left.
+pub fn remaining_capacity_by_priority<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> BTreeMap<i32, i32> {
+    grouped_clients
+        .iter()
+        .map(|(prio, clients)| {
+            let free = clients
+                .iter()
+                .filter(|(client, _)| is_selectable(client, &HashSet::new(), now))
+                .map(|(client, current_jobs)| remaining_capacity(client, *current_jobs, now).max(0))
+                .sum();
+            (*prio, free)
+        })
+        .collect()
+}
+
+/// Roughly estimates how long the available clients take to finish the jobs assigned to them
+///
+/// Assumes every job takes `avg_job_duration`, no new jobs arrive and jobs stay on their client.
+/// Each client works through its jobs in rounds of `maximum_jobs` at a time, the queue is empty once
+/// the client with the most rounds is done. Jobs already running are counted as if they just started.
+/// Unavailable clients aren't processing anything, their jobs are left out instead of stalling the estimate.
+pub fn estimate_drain<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    avg_job_duration: Duration,
+    now: &DateTime<Tz>,
+) -> Duration {
+    let rounds = grouped_clients
+        .values()
+        .flatten()
+        .filter(|(client, _)| is_selectable(client, &HashSet::new(), now))
+        .filter_map(|(client, current_jobs)| {
+            let jobs = current_jobs.unwrap_or(0).max(0) as u32;
+            let concurrency = client.maximum_jobs_at(now);
+            (concurrency > 0).then(|| jobs.div_ceil(concurrency as u32))
+        })
+        .max()
+        .unwrap_or(0);
+    avg_job_duration * rounds
+}
+
+/// Roughly estimates how long a new job waits until some client has a free slot for it
+///
+/// Uses the model of `estimate_drain`: every job takes `avg_job_duration` and running jobs count as
+/// just started, so a full client frees its next slot once the round of jobs ahead of it is done.
+/// A client that is outside its availability or in a blackout with an end first waits until it becomes
+/// available, see `next_available_client`, and doesn't work on its jobs in the meantime.
+/// Draining and offline clients and clients without capacity are left out.
+/// Returns `Duration::ZERO` if a slot is free at `now` and `Duration::MAX` if no client can be expected to free one.
+pub fn expected_wait<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    avg_job_duration: Duration,
+    now: &DateTime<Tz>,
+) -> Duration {
+    grouped_clients
+        .values()
+        .flatten()
+        .filter_map(|(client, current_jobs)| {
+            let (_, until_available) = next_available_client(std::slice::from_ref(client), now)?;
+            let concurrency = client.maximum_jobs_at(now);
+            if concurrency <= 0 {
+                return None;
+            }
+            let jobs = current_jobs.unwrap_or(0).max(0);
+            let rounds = if jobs < concurrency { 0 } else { ((jobs - concurrency) / concurrency + 1) as u32 };
+            Some(until_available + avg_job_duration * rounds)
+        })
+        .min()
+        .unwrap_or(Duration::MAX)
+}
+
+/// Where the next jobs would land, see `simulate_assignment`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SimulatedAssignment {
+    /// the number of jobs every client would receive, by client name, clients receiving nothing are left out
+    pub jobs_per_client: HashMap<String, i32>,
+    /// the number of jobs that found no client because the fleet ran out of free slots
+    pub shortfall: i32,
+}
+
+/// Simulates scheduling `n` jobs on the current fleet without writing anything
+///
+/// Every job is assigned with `get_eligible_client_at` and counted against its client before the
+/// next one is placed, exactly like jobs submitted one after another would be. Caps are respected,
+/// once no client has a free slot the simulation stops and the remaining jobs are reported as `shortfall`.
+pub fn simulate_assignment<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    n: i32,
+    now: &DateTime<Tz>,
+) -> SimulatedAssignment {
+    let mut grouped = grouped_clients.to_owned();
+    let mut simulation = SimulatedAssignment::default();
+    for placed in 0..n.max(0) {
+        let (prio, client) = match get_eligible_client_at(&grouped, &[], now) {
+            Ok((client, _, _)) => (client.priority, client.to_owned()),
+            Err(_) => {
+                simulation.shortfall = n - placed;
+                break;
+            }
+        };
+        if let Some(count) = grouped.get_mut(&prio).and_then(|group| group.get_mut(&client)) {
+            *count = Some(count.unwrap_or(0) + 1);
+        }
+        *simulation.jobs_per_client.entry(client.name).or_insert(0) += 1;
+    }
+    simulation
+}
+
+/// Finds priority groups that sit idle even though every higher priority group is full
+///
+/// A group is reported if none of its clients has a job, at least one of them is available with
+/// free slots and there is at least one higher priority group, all of which have no free slots on
+/// any available client. In that state new jobs should be flowing into the group, so it usually
+/// points at clients that are unreachable for the scheduler, e.g. through ignore lists, pools or
+/// misconfigured priorities.
+pub fn starved_priority_groups<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> Vec<i32> {
+    let has_capacity = |clients: &HashMap<Client, Option<i32>>| {
+        clients
+            .iter()
+            .any(|(client, current_jobs)| is_selectable(client, &HashSet::new(), now) && remaining_capacity(client, *current_jobs, now) > 0)
+    };
+    let mut starved = Vec::new();
+    let mut higher_groups_full = None;
+    for (prio, clients) in grouped_clients {
+        let idle = clients.values().all(|current_jobs| current_jobs.unwrap_or(0) == 0);
+        let group_has_capacity = has_capacity(clients);
+        if idle && group_has_capacity && higher_groups_full == Some(true) {
+            starved.push(*prio);
+        }
+        higher_groups_full = Some(higher_groups_full.unwrap_or(true) && !group_has_capacity);
+    }
+    starved
+}
+
+/// Summarizes how evenly jobs are spread as the Gini coefficient of the clients' utilization
+///
+/// Utilization is the current job count divided by the maximum job count at `now`, see
+/// `Client::maximum_jobs_at`, clients that can't take any jobs at `now` are left out. The score ranges from 0, every client is equally utilized, towards 1,
+/// all jobs sit on a single client; with n clients it can't exceed `1 - 1/n`.
+/// Less than two clients or no jobs at all score 0. Availability is not taken into account,
+/// taking clients offline raises the score while they are idle.
+pub fn fairness_score<Tz: TimeZone>(grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>, now: &DateTime<Tz>) -> f64 {
+    let utilization: Vec<f64> = grouped_clients
+        .values()
+        .flatten()
+        .map(|(client, current_jobs)| (current_jobs.unwrap_or(0), client.maximum_jobs_at(now)))
+        .filter(|(_, maximum_jobs)| *maximum_jobs > 0)
+        .map(|(current_jobs, maximum_jobs)| current_jobs as f64 / maximum_jobs as f64)
+        .collect();
+    let n = utilization.len() as f64;
+    let sum: f64 = utilization.iter().sum();
+    if utilization.len() < 2 || sum == 0.0 {
+        return 0.0;
+    }
+    let mut differences = 0.0;
+    for a in &utilization {
+        for b in &utilization {
+            differences += (a - b).abs();
+        }
+    }
+    // mean absolute difference relative to twice the mean
+    differences / (2.0 * n * sum)
+}
+
+/// The most jobs each client ran at the same time between `since` and `until`
+///
+/// A job runs from its `started_at` to its `completed_at`, jobs that never started are skipped and
+/// jobs without `completed_at` are counted as running until `until`. A job finishing at the same
+/// instant another one starts doesn't overlap with it. Clients without a job in the window are left out.
+pub fn peak_concurrency(jobs: &[Job], since: DateTime<Utc>, until: DateTime<Utc>) -> HashMap<ObjectId, i32> {
+    // (time, +1 for a start or -1 for an end) per client
+    let mut events: HashMap<&ObjectId, Vec<(DateTime<Utc>, i32)>> = HashMap::new();
+    for job in jobs {
+        let start = match job.started_at {
+            Some(start) => start.0.max(since),
+            None => continue,
+        };
+        let end = job.completed_at.map_or(until, |end| end.0.min(until));
+        if start >= end {
+            continue;
+        }
+        let client_events = events.entry(&job.assigned_client.id).or_default();
+        client_events.push((start, 1));
+        client_events.push((end, -1));
+    }
+    events
+        .into_iter()
+        .map(|(client_id, mut client_events)| {
+            // ends sort before starts at the same instant
+            client_events.sort();
+            let mut running = 0;
+            let mut peak = 0;
+            for (_, change) in client_events {
+                running += change;
+                peak = peak.max(running);
+            }
+            (client_id.to_owned(), peak)
+        })
+        .collect()
+}
+
+/// Suggests a `maximum_jobs` per client: its peak concurrency in the window plus `headroom`
+///
+/// `headroom` is a fraction of the peak, e.g. 0.25 for 25% more, rounded up, and every client
+/// with jobs in the window is suggested at least 1. This relies on `started_at` and `completed_at`
+/// being recorded, see `db::claim_next_job` and `db::mark_job_completed`; jobs from before those
+/// were tracked are skipped and the suggestion only reflects the load the scheduler let through,
+/// it can't tell whether a client would have handled more.
+pub fn suggest_maximum_jobs(jobs: &[Job], since: DateTime<Utc>, until: DateTime<Utc>, headroom: f64) -> HashMap<ObjectId, i32> {
+    peak_concurrency(jobs, since, until)
+        .into_iter()
+        .map(|(client_id, peak)| (client_id, ((peak as f64 * (1.0 + headroom)).ceil() as i32).max(1)))
+        .collect()
+}
+
+/// Moving a queued job from one client to another, see `plan_rebalance`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobMove {
+    pub job_id: ObjectId,
+    pub from: ObjectId,
+    pub to: ObjectId,
+}
+
+/// Plans moving queued jobs from lower priority clients to higher priority clients with free slots
+///
+/// Only queued jobs are eligible to move, a job a worker already started stays where it is.
+/// Free slots are filled from the highest priority group down, each slot going to the least loaded
+/// available client, and jobs are taken from the lowest priority clients first.
+/// Jobs without an id or assigned to a client that isn't part of `grouped_clients` are left alone.
+/// Nothing is written, apply the plan with `db::apply_job_moves`.
+pub fn plan_rebalance<Tz: TimeZone>(
+    jobs: &[Job],
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> Vec<JobMove> {
+    // (priority, client, current jobs) for every client, in scheduling order
+    let mut clients: Vec<(i32, &Client, i32)> = Vec::new();
+    for (prio, group) in grouped_clients {
+        let mut group: Vec<(i32, &Client, i32)> = group
+            .iter()
+            .filter(|(client, _)| client.id.is_some())
+            .map(|(client, count)| (*prio, client, count.unwrap_or(0)))
+            .collect();
+        group.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        clients.extend(group);
+    }
+    let priority_of = |id: &ObjectId| clients.iter().find(|(_, c, _)| c.id.as_ref() == Some(id)).map(|(prio, _, _)| *prio);
+
+    // movable jobs, lowest priority source first
+    let mut movable: Vec<(i32, &Job)> = jobs
+        .iter()
+        .filter(|job| job.status.is_queued() && job.id.is_some())
+        .filter_map(|job| priority_of(&job.assigned_client.id).map(|prio| (prio, job)))
+        .collect();
+    movable.sort_by_key(|(prio, _)| std::cmp::Reverse(*prio));
+
+    let mut moves = Vec::new();
+    for (job_prio, job) in movable {
+        let target = clients
+            .iter_mut()
+            .filter(|(prio, client, count)| {
+                *prio < job_prio && is_selectable(client, &HashSet::new(), now) && remaining_capacity(client, Some(*count), now) > 0
+            })
+            .min_by_key(|(prio, _, count)| (*prio, *count));
+        if let Some((_, client, count)) = target {
+            *count += 1;
+            let to = client.id.to_owned().unwrap();
+            let from = job.assigned_client.id.to_owned();
+            if let Some((_, _, source_count)) = clients.iter_mut().find(|(_, c, _)| c.id.as_ref() == Some(&from)) {
+                *source_count -= 1;
+            }
+            moves.push(JobMove { job_id: job.id.to_owned().unwrap(), from, to });
+        }
+    }
+    moves
+}
+
+/// Plans which client each queued job goes to, honoring job and client priorities together
+///
+/// Jobs are placed one after another, most urgent first: by job priority, lower values first, then
+/// oldest first by id, jobs without an id after those with one, then by path. Each job goes to the
+/// least loaded available client of the highest priority group that has a free slot and is allowed
+/// by the job's `allowed_clients`, equally loaded clients are taken in name order. Every placed job
+/// counts against its client before the next one is placed, so urgent jobs take the best slots and
+/// less urgent jobs fill up lower priority groups. Jobs that don't fit anywhere and jobs that aren't
+/// queued are left out. Nothing is written.
+pub fn plan_assignment<'a, 'j, Tz: TimeZone>(
+    jobs: &'j [Job],
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> Vec<(&'j Job, &'a Client)> {
+    // (priority, client, current jobs) for every available client, in scheduling order
+    let mut clients: Vec<(i32, &Client, i32)> = Vec::new();
+    for (prio, group) in grouped_clients {
+        let mut group: Vec<(i32, &Client, i32)> = group
+            .iter()
+            .filter(|(client, _)| is_selectable(client, &HashSet::new(), now))
+            .map(|(client, count)| (*prio, client, count.unwrap_or(0)))
+            .collect();
+        group.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        clients.extend(group);
+    }
+
+    let mut queued: Vec<&Job> = jobs.iter().filter(|job| job.status.is_queued()).collect();
+    queued.sort_by(|a, b| {
+        a.priority
+            .cmp(&b.priority)
+            .then_with(|| a.id.is_none().cmp(&b.id.is_none()))
+            .then_with(|| a.id.as_ref().map(|id| id.bytes()).cmp(&b.id.as_ref().map(|id| id.bytes())))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    let mut plan = Vec::new();
+    for job in queued {
+        let allowed = |client: &Client| {
+            job.allowed_clients.is_empty() || client.id.as_ref().is_some_and(|id| job.allowed_clients.contains(id))
+        };
+        let target = clients
+            .iter_mut()
+            .filter(|(_, client, count)| allowed(client) && remaining_capacity(client, Some(*count), now) > 0)
+            .min_by_key(|(prio, _, count)| (*prio, *count));
+        if let Some((_, client, count)) = target {
+            *count += 1;
+            plan.push((job, *client));
+        }
+    }
+    plan
+}
+
+/// The most recent fleet load samples, for trend graphs
+///
+/// Keeps at most `capacity` samples, recording more drops the oldest one, so memory stays fixed no
+/// matter how long the history runs. Record at a fixed interval so that a window of samples is a
+/// window of time.
+#[derive(Debug, Clone)]
+pub struct LoadHistory {
+    samples: VecDeque<i32>,
+    capacity: usize,
+}
+
+impl LoadHistory {
+    pub fn new(capacity: usize) -> Self {
+        LoadHistory { samples: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn record(&mut self, total_load: i32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(total_load);
+    }
+
+    /// Records the sum of job counts as returned by `db::get_machine_jobcount`
+    pub fn record_counts(&mut self, job_counts: &HashMap<String, i32>) {
+        self.record(job_counts.values().sum());
+    }
+
+    /// The average of the last `window` samples, fewer if not as many were recorded, `None` without samples
+    pub fn average(&self, window: usize) -> Option<f64> {
+        let recent: Vec<i32> = self.recent(window).collect();
+        if recent.is_empty() {
+            return None;
+        }
+        Some(recent.iter().map(|load| *load as f64).sum::<f64>() / recent.len() as f64)
+    }
+
+    /// The highest of the last `window` samples, `None` without samples
+    pub fn peak(&self, window: usize) -> Option<i32> {
+        self.recent(window).max()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    fn recent(&self, window: usize) -> impl Iterator<Item = i32> + '_ {
+        self.samples.iter().rev().take(window).copied()
+    }
+}
+
+/// A suspicious client configuration reported by `analyze_client_config`, clients are named
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// `stronger` can run more jobs at once than `weaker` but only gets jobs once `weaker` is full
+    PriorityInversion { stronger: String, weaker: String },
+    /// the client is online but never receives a job, since its `maximum_jobs` is zero or less
+    NoCapacity { client: String },
+    /// the availability of the client can't be parsed, see `availability::validate_client_availability`
+    MalformedAvailability { client: String, error: String },
+}
+
+/// Reports client configurations that are probably mistakes, without changing anything
+///
+/// Capacity is only a hint at how fast a machine is, so a priority inversion may well be intended,
+/// e.g. for a machine that is shared with other work. Inversions are reported for every pair of
+/// clients in which the client with the higher `maximum_jobs` has the lower priority.
+/// Warnings are sorted by kind, then by client name.
+pub fn analyze_client_config(clients: &[Client]) -> Vec<ConfigWarning> {
+    let mut sorted: Vec<&Client> = clients.iter().collect();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut warnings = Vec::new();
+    for stronger in &sorted {
+        for weaker in &sorted {
+            if stronger.maximum_jobs > weaker.maximum_jobs && stronger.priority > weaker.priority {
+                warnings.push(ConfigWarning::PriorityInversion { stronger: stronger.name.to_owned(), weaker: weaker.name.to_owned() });
+            }
+        }
+    }
+    warnings.extend(
+        sorted
+            .iter()
+            .filter(|client| client.online && client.maximum_jobs <= 0)
+            .map(|client| ConfigWarning::NoCapacity { client: client.name.to_owned() }),
+    );
+    warnings.extend(
+        sorted
+            .iter()
+            .filter_map(|client| validate_client_availability(std::slice::from_ref(*client)).pop())
+            .map(|(client, error)| ConfigWarning::MalformedAvailability { client: client.name, error }),
+    );
+    warnings
+}
+
+/// Finds clients that claim to be online but haven't sent a heartbeat for longer than `max_staleness`
+///
+/// Those clients are still picked by the scheduler and silently swallow jobs, force them offline
+/// with `db::set_client_online`. Clients that never sent a heartbeat have nothing to compare against
+/// and are not reported.
+pub fn stale_online_clients<'a, Tz: TimeZone>(clients: &'a [Client], now: &DateTime<Tz>, max_staleness: chrono::Duration) -> Vec<&'a Client> {
+    let now = now.with_timezone(&Utc);
+    clients
+        .iter()
+        .filter(|client| client.online && client.last_seen.is_some_and(|seen| now - seen.0 > max_staleness))
+        .collect()
+}
+
+/// Compares the clients read from the database against the expected ones
+///
+/// Clients are matched by id if the expected client has one and by name otherwise,
+/// since configs kept in version control usually don't know the ids Mongo assigned.
+/// `Online` is runtime state rather than configuration and is not compared.
+pub fn diff_fleets(db_clients: &[Client], expected_clients: &[Client]) -> FleetDiff {
+    let mut diff = FleetDiff::default();
+    let mut matched = vec![false; db_clients.len()];
+    for expected in expected_clients {
+        let position = db_clients.iter().position(|actual| match &expected.id {
+            Some(id) => actual.id.as_ref() == Some(id),
+            None => actual.name == expected.name,
+        });
+        match position {
+            Some(idx) => {
+                matched[idx] = true;
+                let fields = changed_fields(&db_clients[idx], expected);
+                if !fields.is_empty() {
+                    diff.changed.push(ClientChange { name: expected.name.to_owned(), fields });
+                }
+            }
+            None => diff.removed.push(expected.to_owned()),
+        }
+    }
+    for (client, _) in db_clients.iter().zip(matched).filter(|(_, matched)| !matched) {
+        diff.added.push(client.to_owned());
+    }
+    diff
+}
+
+/// Compares the configuration fields of two clients one by one,
+/// `PartialEq` is not used since it only looks at the id or name.
+fn changed_fields(actual: &Client, expected: &Client) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if actual.name != expected.name {
+        fields.push("Name");
+    }
+    if actual.availability_start != expected.availability_start {
+        fields.push("AvailabilityStart");
+    }
+    if actual.availability_end != expected.availability_end {
+        fields.push("AvailabilityEnd");
+    }
+    if actual.maximum_jobs != expected.maximum_jobs {
+        fields.push("MaximumJobs");
+    }
+    if actual.priority != expected.priority {
+        fields.push("Priority");
+    }
+    if actual.ignore_online != expected.ignore_online {
+        fields.push("IgnoreOnline");
+    }
+    if actual.ignore_availability != expected.ignore_availability {
+        fields.push("IgnoreAvailability");
+    }
+    if actual.blackout_start != expected.blackout_start {
+        fields.push("BlackoutStart");
+    }
+    if actual.blackout_end != expected.blackout_end {
+        fields.push("BlackoutEnd");
+    }
+    if actual.default_parameters != expected.default_parameters {
+        fields.push("DefaultParameters");
+    }
+    if actual.pool != expected.pool {
+        fields.push("Pool");
+    }
+    if actual.draining != expected.draining {
+        fields.push("Draining");
+    }
+    if actual.maximum_minutes != expected.maximum_minutes {
+        fields.push("MaximumMinutes");
+    }
+    if actual.capacity_schedule != expected.capacity_schedule {
+        fields.push("CapacitySchedule");
+    }
+    if actual.availability_cron != expected.availability_cron {
+        fields.push("AvailabilityCron");
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(jobs: &[(&Client, i32)]) -> HashMap<String, i32> {
+        jobs.iter().map(|(client, count)| (client.id.as_ref().unwrap().to_string(), *count)).collect()
+    }
+
+    fn client(name: &str, priority: i32, maximum_jobs: i32) -> Client {
+        Client {
+            id: Some(bson::oid::ObjectId::new()),
+            name: name.to_string(),
+            maximum_jobs,
+            priority,
+            online: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_schedule_state() {
+        let now = chrono::Utc::now();
+        let full = client("full", 1, 1);
+        let free = client("free", 2, 1);
+        let mut counts = HashMap::new();
+        counts.insert(full.id.as_ref().unwrap().to_string(), 1);
+        let state = schedule_state(vec![full, free], counts, &now);
+        assert_eq!(state.next_client.as_deref(), Some("free"));
+        assert!(!state.priority_groups[&1][0].eligible);
+        assert_eq!(state.priority_groups[&1][0].current_jobs, Some(1));
+        assert!(state.priority_groups[&2][0].eligible);
+
+        let json: serde_json::Value = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["PriorityGroups"]["2"][0]["Client"]["Name"], "free");
+        assert_eq!(json["NextClient"], "free");
+    }
+
+    #[test]
+    fn test_eligible_counts_by_priority() {
+        let now = chrono::Utc::now();
+        let full = client("full", 1, 1);
+        let free = client("free", 1, 2);
+        let offline = Client { online: false, ..client("offline", 2, 2) };
+        let mut counts = HashMap::new();
+        counts.insert(full.id.as_ref().unwrap().to_string(), 1);
+        let grouped = group_clients(vec![full, free, offline], counts);
+        let by_priority = eligible_counts_by_priority(&grouped, &now);
+        assert_eq!(by_priority[&1], (1, 2));
+        assert_eq!(by_priority[&2], (0, 1));
+    }
+
+    #[test]
+    fn test_starved_priority_groups() {
+        let now = chrono::Utc::now();
+        let full = client("full", 1, 2);
+        let idle = client("idle", 2, 2);
+        let also_idle = client("also_idle", 3, 2);
+        let mut counts = HashMap::new();
+        counts.insert(full.id.as_ref().unwrap().to_string(), 2);
+        let grouped = group_clients(vec![full.clone(), idle.clone(), also_idle], counts.clone());
+        // the third group is behind the second one, which still has room
+        assert_eq!(starved_priority_groups(&grouped, &now), vec![2]);
+
+        let grouped = group_clients(vec![Client { maximum_jobs: 3, ..full }, idle], counts);
+        assert!(starved_priority_groups(&grouped, &now).is_empty());
+    }
+
+    #[test]
+    fn test_remaining_capacity_by_priority() {
+        let now = chrono::Utc::now();
+        let online = client("online", 1, 4);
+        let offline = Client { online: false, ..client("offline", 1, 4) };
+        let overbooked = client("overbooked", 2, 1);
+        let backup = client("backup", 2, 3);
+        let grouped = group_clients(
+            vec![online.clone(), offline, overbooked.clone(), backup],
+            counts(&[(&online, 1), (&overbooked, 3)]),
+        );
+        let capacity = remaining_capacity_by_priority(&grouped, &now);
+        assert_eq!(capacity.into_iter().collect::<Vec<_>>(), vec![(1, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn test_estimate_drain() {
+        let now = chrono::Utc::now();
+        let hour = Duration::from_secs(3600);
+        let wide = client("wide", 1, 4);
+        let narrow = client("narrow", 2, 2);
+        let offline = Client { online: false, ..client("offline", 2, 1) };
+        let grouped = group_clients(
+            vec![wide.clone(), narrow.clone(), offline.clone()],
+            counts(&[(&wide, 8), (&narrow, 3), (&offline, 10)]),
+        );
+        // wide needs 2 rounds, narrow needs 2 rounds, the offline client is left out
+        assert_eq!(estimate_drain(&grouped, hour, &now), 2 * hour);
+        assert_eq!(estimate_drain(&group_clients(vec![wide], HashMap::new()), hour, &now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_expected_wait() {
+        let now = chrono::Utc.with_ymd_and_hms(2024, 3, 4, 12, 0, 0).unwrap();
+        let hour = Duration::from_secs(3600);
+        let full = client("full", 1, 2);
+        let overloaded = client("overloaded", 2, 2);
+        let evening = Client { availability_start: "18:00".to_string(), availability_end: "23:00".to_string(), ..client("evening", 2, 2) };
+        let draining = Client { draining: true, ..client("draining", 3, 2) };
+        let grouped = group_clients(
+            vec![full.clone(), overloaded.clone(), evening.clone(), draining.clone()],
+            counts(&[(&full, 4), (&overloaded, 5), (&evening, 0), (&draining, 0)]),
+        );
+        // full frees a slot after two rounds, evening opens in six hours, draining is left out
+        assert_eq!(expected_wait(&grouped, hour, &now), 2 * hour);
+        let grouped = group_clients(vec![full.clone(), evening], counts(&[(&full, 1)]));
+        assert_eq!(expected_wait(&grouped, hour, &now), Duration::ZERO);
+        assert_eq!(expected_wait(&group_clients(vec![draining], HashMap::new()), hour, &now), Duration::MAX);
+    }
+
+    #[test]
+    fn test_simulate_assignment() {
+        let now = chrono::Utc::now();
+        let first = client("first", 1, 2);
+        let second = client("second", 2, 3);
+        let grouped = group_clients(vec![first.clone(), second.clone()], counts(&[(&first, 1)]));
+        let simulation = simulate_assignment(&grouped, 3, &now);
+        assert_eq!(simulation.jobs_per_client, vec![("first".to_string(), 1), ("second".to_string(), 2)].into_iter().collect());
+        assert_eq!(simulation.shortfall, 0);
+
+        let simulation = simulate_assignment(&grouped, 6, &now);
+        assert_eq!(simulation.jobs_per_client.values().sum::<i32>(), 4);
+        assert_eq!(simulation.shortfall, 2);
+    }
+
+    #[test]
+    fn test_plan_assignment() {
+        let now = chrono::Utc::now();
+        let primary = client("primary", 1, 2);
+        let secondary = client("secondary", 2, 1);
+        let grouped = group_clients(vec![primary.clone(), secondary.clone()], counts(&[(&primary, 1)]));
+        let job = |name: &str, priority: i32| Job { id: Some(ObjectId::new()), name: name.to_string(), priority, ..Default::default() };
+        let pinned = Job { allowed_clients: vec![secondary.id.unwrap()], ..job("pinned", 0) };
+        let jobs = vec![job("routine", 5), job("urgent", 1), job("later routine", 5), pinned, job("overflow", 9)];
+        let plan: Vec<(&str, &str)> = plan_assignment(&jobs, &grouped, &now).iter().map(|(j, c)| (j.name.as_str(), c.name.as_str())).collect();
+        // the pinned job takes the only slot of secondary, so routine jobs beyond the first don't fit
+        assert_eq!(plan, vec![("pinned", "secondary"), ("urgent", "primary")]);
+
+        let roomy = client("roomy", 3, 5);
+        let grouped = group_clients(vec![primary.clone(), roomy], counts(&[(&primary, 1)]));
+        let plan: Vec<(&str, &str)> = plan_assignment(&jobs[..3], &grouped, &now).iter().map(|(j, c)| (j.name.as_str(), c.name.as_str())).collect();
+        assert_eq!(plan, vec![("urgent", "primary"), ("routine", "roomy"), ("later routine", "roomy")]);
+    }
+
+    #[test]
+    fn test_load_history() {
+        let mut history = LoadHistory::new(3);
+        assert_eq!(history.average(3), None);
+        assert_eq!(history.peak(3), None);
+        for load in [4, 8, 2, 6].iter() {
+            history.record(*load);
+        }
+        // the first sample was dropped
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.average(3), Some(16.0 / 3.0));
+        assert_eq!(history.average(2), Some(4.0));
+        assert_eq!(history.peak(10), Some(8));
+        assert_eq!(history.peak(1), Some(6));
+
+        let a = client("a", 1, 4);
+        let b = client("b", 1, 4);
+        history.record_counts(&counts(&[(&a, 3), (&b, 9)]));
+        assert_eq!(history.peak(1), Some(12));
+    }
+
+    #[test]
+    fn test_analyze_client_config() {
+        let fast = client("fast", 2, 8);
+        let slow = client("slow", 1, 2);
+        let idle = Client { online: true, ..client("idle", 1, 0) };
+        let typo = Client { availability_start: "8 am".to_string(), availability_end: "18:00".to_string(), ..client("typo", 1, 2) };
+        let warnings = analyze_client_config(&[fast, slow, idle, typo]);
+        assert_eq!(
+            warnings[..4],
+            [
+                ConfigWarning::PriorityInversion { stronger: "fast".to_string(), weaker: "idle".to_string() },
+                ConfigWarning::PriorityInversion { stronger: "fast".to_string(), weaker: "slow".to_string() },
+                ConfigWarning::PriorityInversion { stronger: "fast".to_string(), weaker: "typo".to_string() },
+                ConfigWarning::NoCapacity { client: "idle".to_string() },
+            ]
+        );
+        assert!(matches!(&warnings[4], ConfigWarning::MalformedAvailability { client, .. } if client == "typo"));
+        assert_eq!(warnings.len(), 5);
+        assert!(analyze_client_config(&[client("a", 1, 4), client("b", 2, 2)]).is_empty());
+    }
+
+    #[test]
+    fn test_fairness_score() {
+        let now = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let small = client("small", 1, 2);
+        let large = client("large", 1, 4);
+        let even = group_clients(vec![small.clone(), large.clone()], counts(&[(&small, 1), (&large, 2)]));
+        assert_eq!(fairness_score(&even, &now), 0.0);
+
+        let skewed = group_clients(vec![small.clone(), large.clone()], counts(&[(&large, 4)]));
+        assert!((fairness_score(&skewed, &now) - 0.5).abs() < 1e-9);
+        assert_eq!(fairness_score(&group_clients(vec![small.clone()], HashMap::new()), &now), 0.0);
+
+        // at noon the small client may take 4 jobs, so both are half utilized
+        let noon = crate::availability::CapacityOverride { start: "11:00".to_string(), end: "13:00".to_string(), maximum_jobs: 4 };
+        let small = Client { capacity_schedule: vec![noon], ..small };
+        let overridden = group_clients(vec![small.clone(), large.clone()], counts(&[(&small, 2), (&large, 2)]));
+        assert_eq!(fairness_score(&overridden, &now), 0.0);
+    }
+
+    #[test]
+    fn test_suggest_maximum_jobs() {
+        use chrono::Duration;
+        let since = Utc.with_ymd_and_hms(2021, 5, 1, 0, 0, 0).unwrap();
+        let until = since + Duration::hours(10);
+        let worker = client("worker", 1, 1);
+        let job = |start: i64, end: Option<i64>| Job {
+            assigned_client: worker.to_dbref("").unwrap(),
+            started_at: Some((since + Duration::hours(start)).into()),
+            completed_at: end.map(|end| (since + Duration::hours(end)).into()),
+            ..Default::default()
+        };
+        let jobs = vec![
+            job(1, Some(3)),
+            job(2, Some(4)),
+            // starts right when the first one ends
+            job(3, Some(5)),
+            // still running
+            job(3, None),
+            // finished before the window
+            job(-5, Some(-1)),
+            Job { started_at: None, ..job(0, None) },
+        ];
+        let id = worker.id.clone().unwrap();
+        assert_eq!(peak_concurrency(&jobs, since, until)[&id], 3);
+        assert_eq!(suggest_maximum_jobs(&jobs, since, until, 0.25)[&id], 4);
+        assert!(suggest_maximum_jobs(&jobs[4..5], since, until, 0.25).is_empty());
+    }
+
+    #[test]
+    fn test_plan_rebalance() {
+        let now = chrono::Utc::now();
+        let promoted = client("promoted", 1, 3);
+        let middle = client("middle", 2, 2);
+        let bottom = client("bottom", 3, 5);
+        let job = |client: &Client, status| Job {
+            id: Some(ObjectId::new()),
+            assigned_client: client.to_dbref("").unwrap(),
+            status,
+            ..Default::default()
+        };
+        let jobs = vec![
+            job(&middle, crate::JobStatus::Queued),
+            job(&bottom, crate::JobStatus::Running),
+            job(&bottom, crate::JobStatus::Queued),
+            job(&bottom, crate::JobStatus::Queued),
+        ];
+        let mut counts = HashMap::new();
+        counts.insert(middle.id.as_ref().unwrap().to_string(), 1);
+        counts.insert(bottom.id.as_ref().unwrap().to_string(), 3);
+        let grouped = group_clients(vec![promoted.clone(), middle.clone(), bottom.clone()], counts);
+
+        let moves = plan_rebalance(&jobs, &grouped, &now);
+        // the queued jobs of the bottom client move first, the running one stays,
+        // then the job of the middle client takes the last free slot of the promoted client
+        assert_eq!(moves.len(), 3);
+        assert_eq!(moves[0], JobMove { job_id: jobs[2].id.clone().unwrap(), from: bottom.id.clone().unwrap(), to: promoted.id.clone().unwrap() });
+        assert_eq!(moves[1].job_id, jobs[3].id.clone().unwrap());
+        assert_eq!(moves[1].to, promoted.id.clone().unwrap());
+        assert_eq!(moves[2], JobMove { job_id: jobs[0].id.clone().unwrap(), from: middle.id.clone().unwrap(), to: promoted.id.clone().unwrap() });
+    }
+
+    #[test]
+    fn test_stale_online_clients() {
+        let now = Utc::now();
+        let seen = |minutes| Some((now - chrono::Duration::minutes(minutes)).into());
+        let clients = vec![
+            Client { last_seen: seen(1), ..client("fresh", 1, 1) },
+            Client { last_seen: seen(30), ..client("stale", 1, 1) },
+            Client { last_seen: seen(30), online: false, ..client("offline", 1, 1) },
+            client("never_seen", 1, 1),
+        ];
+        let stale = stale_online_clients(&clients, &now, chrono::Duration::minutes(5));
+        assert_eq!(stale.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["stale"]);
+    }
+
+    #[test]
+    fn test_diff_fleets() {
+        let unchanged = client("unchanged", 1, 2);
+        let changed = client("changed", 1, 2);
+        let removed = client("removed", 1, 2);
+        let added = client("added", 1, 2);
+        let db_clients = vec![unchanged.clone(), Client { maximum_jobs: 4, priority: 2, online: false, ..changed.clone() }, added];
+
+        // the expected config doesn't know about ids for this one
+        let expected = vec![Client { id: None, ..unchanged }, changed, removed];
+        let diff = diff_fleets(&db_clients, &expected);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].name, "added");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].name, "removed");
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].name, "changed");
+        assert_eq!(diff.changed[0].fields, vec!["MaximumJobs", "Priority"]);
+        assert!(!diff.is_empty());
+        assert!(diff_fleets(&db_clients, &db_clients).is_empty());
+    }
+}
@@ -1,17 +1,81 @@
+pub mod availability;
+pub mod cfg;
+#[cfg(feature = "mongodb")]
 pub mod db;
+pub mod fleet;
 pub mod log;
+#[cfg(feature = "mongodb")]
+pub mod scheduler;
+pub mod strategy;
+pub mod worker;
+#[cfg(feature = "mongodb")]
 pub use mongodb::sync::Client as MongoClient;
+#[cfg(feature = "mongodb")]
 pub use mongodb::error::Error as MongoError;
 
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
     error::Error,
     fmt,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::BufReader,
+    path::Path,
 };
-use serde::{Deserialize, Serialize, Serializer};
+use availability::{AvailabilityState, CapacityOverride};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use rand::Rng;
+use strategy::{LeastLoaded, SelectionContext, SelectionStrategy};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 pub struct InfuserError {
+    pub kind: InfuserErrorKind,
     pub message: String,
+    /// the underlying error, if this error wraps one
+    pub source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+/// What went wrong, so callers can react to specific failures without matching on messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfuserErrorKind {
+    /// the fleet has no clients at all, which usually means a misconfiguration
+    EmptyFleet,
+    /// there are clients, but none of them can take a job right now
+    NoEligibleClient,
+    /// the fleet as a whole runs as many jobs as allowed, see `get_eligible_client_within_global_limit`
+    GlobalCapacityReached,
+    /// job assignment was paused by an operator, see `db::set_scheduler_paused`
+    Paused,
+    /// the job or client an operation refers to doesn't exist
+    NotFound,
+    /// the document was changed by someone else since it was read
+    Conflict,
+    /// an operation didn't finish within its time budget
+    Timeout,
+    Other,
+}
+
+impl InfuserError {
+    pub fn new(message: &str) -> Self {
+        InfuserError::with_kind(InfuserErrorKind::Other, message)
+    }
+
+    pub fn with_kind(kind: InfuserErrorKind, message: &str) -> Self {
+        InfuserError {
+            kind,
+            message: message.to_owned(),
+            source: None,
+        }
+    }
+
+    /// Creates an error that keeps `source` as its cause
+    pub fn with_source(message: &str, source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        InfuserError {
+            source: Some(source.into()),
+            ..InfuserError::new(message)
+        }
+    }
 }
 
 impl fmt::Debug for InfuserError {
@@ -26,9 +90,20 @@ impl fmt::Display for InfuserError {
     }
 }
 
-impl Error for InfuserError {}
+impl Error for InfuserError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+#[cfg(feature = "mongodb")]
+impl From<MongoError> for InfuserError {
+    fn from(e: MongoError) -> Self {
+        InfuserError::with_source(&format!("database error: {}", e), e)
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Eq)]
 #[serde(rename_all = "PascalCase")]
 pub struct Client {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
@@ -39,7 +114,39 @@ pub struct Client {
     pub maximum_jobs: i32,
     pub priority: i32,
     pub online: bool,
+    /// bypasses only the `online` check, the availability window, blackouts and draining still apply
     pub ignore_online: bool,
+    /// bypasses only the availability window and cron, the client is eligible at any time of day;
+    /// `online`, blackouts and draining still apply. Combine with `ignore_online` for always-on clients
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ignore_availability: bool,
+    /// start of a maintenance period during which the client doesn't receive jobs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blackout_start: Option<bson::DateTime>,
+    /// end of the maintenance period (exclusive)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blackout_end: Option<bson::DateTime>,
+    /// parameters every job on this client gets, see `merge_parameters`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub default_parameters: Vec<String>,
+    /// the logical pool the client belongs to, see `get_eligible_client_in_pool`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pool: String,
+    /// a draining client keeps its jobs but doesn't receive new ones
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub draining: bool,
+    /// budget of concurrent estimated job minutes, see `get_eligible_client_by_minutes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum_minutes: Option<i32>,
+    /// caps replacing `maximum_jobs` during parts of the day, see `Client::maximum_jobs_at`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capacity_schedule: Vec<CapacityOverride>,
+    /// cron expression replacing the daily window, see `Client::within_availability_window`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub availability_cron: Option<String>,
+    /// last heartbeat of the client, see `db::record_heartbeat`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<bson::DateTime>,
 }
 
 impl PartialEq for Client {
@@ -51,18 +158,110 @@ impl PartialEq for Client {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Client {
+    /// The DBRef a job uses to reference this client
+    ///
+    /// `db_name` becomes the `$db` of the reference, an empty string leaves it out,
+    /// see `Config::assigned_client`. Fails if the client has no id, i.e. was never stored.
+    pub fn to_dbref(&self, db_name: &str) -> Result<AssignedClient, InfuserError> {
+        match &self.id {
+            Some(id) => Ok(AssignedClient { collection: "clients".to_string(), id: id.to_owned(), db: db_name.to_owned() }),
+            None => Err(InfuserError::with_kind(
+                InfuserErrorKind::NotFound,
+                &format!("client {} has no id and can't be referenced", self.name),
+            )),
+        }
+    }
+
+    /// Checks whether `now` falls into the client's blackout period
+    ///
+    /// A missing start means the blackout is already in effect, a missing end means it lasts
+    /// until further notice. Without both fields there is no blackout.
+    pub fn in_blackout<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        if self.blackout_start.is_none() && self.blackout_end.is_none() {
+            return false;
+        }
+        let now = now.with_timezone(&Utc);
+        let started = self.blackout_start.is_none_or(|start| start.0 <= now);
+        let ended = self.blackout_end.is_some_and(|end| end.0 <= now);
+        started && !ended
+    }
+}
+
+impl Hash for Client {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // keep in line with PartialEq: clients without an id are identified by name
+        match &self.id {
+            Some(id) => id.hash(state),
+            None => self.name.hash(state),
+        }
+    }
+}
+
+/// A job document of the `jobs` collection
+///
+/// With the `field-aliases` feature, job documents written by other tools can be read as well:
+/// `Title` is accepted for `Name`, `EpisodeTitle` for `Subtitle` and `FilePath` for `Path`.
+/// Jobs are always written with the regular field names.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct Job {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<bson::oid::ObjectId>,
+    #[cfg_attr(feature = "field-aliases", serde(alias = "Title"))]
     pub name: String,
+    #[cfg_attr(feature = "field-aliases", serde(alias = "FilePath"))]
     pub path: String,
+    #[cfg_attr(feature = "field-aliases", serde(alias = "EpisodeTitle"))]
     pub subtitle: String,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub custom_parameters: Vec<String>,
     pub assigned_client: AssignedClient,
+    /// set once the job is finished, see `db::mark_job_completed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<bson::DateTime>,
+    /// when a worker claimed the job, see `db::claim_next_job`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<bson::DateTime>,
+    /// set by operators to ask the worker to stop the job, see `db::request_cancel`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub cancel_requested: bool,
+    /// expected processing time, used by `get_eligible_client_by_minutes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_minutes: Option<i32>,
+    #[serde(default, skip_serializing_if = "JobStatus::is_queued")]
+    pub status: JobStatus,
+    /// the only clients the job may run on, empty means any client, see `get_eligible_client_for_job`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_clients: Vec<bson::oid::ObjectId>,
+    /// caller supplied key identifying the job across retries, see `db::insert_job_idempotent`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// incremented on every versioned update, see `db::update_job_versioned`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub version: i64,
+    /// like client priorities, lower values are more urgent, see `fleet::plan_assignment`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// jobs of the same group avoid sharing a client, see `get_eligible_client_with_anti_affinity`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anti_affinity_group: Option<String>,
+}
+
+/// Whether a worker has started processing a job, jobs without a status are queued
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    #[default]
+    Queued,
+    Running,
+}
+
+impl JobStatus {
+    pub fn is_queued(&self) -> bool {
+        *self == JobStatus::Queued
+    }
 }
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct JobJson {
@@ -74,6 +273,8 @@ pub struct JobJson {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub custom_parameters: Vec<String>,
     pub assigned_client: AssignedClientJson,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub cancel_requested: bool,
 }
 
 impl From<Job> for JobJson {
@@ -84,15 +285,30 @@ impl From<Job> for JobJson {
             name: job.name,
             path: job.path,
             subtitle: job.subtitle,
-            custom_parameters: job.custom_parameters
+            custom_parameters: job.custom_parameters,
+            cancel_requested: job.cancel_requested,
         }
     }
 }
 
+impl Job {
+    /// Encodes the job as a raw BSON document, the same representation Mongo stores
+    pub fn to_bson_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = Vec::new();
+        bson::to_document(self)?.to_writer(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Decodes a job previously encoded with `to_bson_bytes`
+    pub fn from_bson_bytes(mut bytes: &[u8]) -> Result<Job, Box<dyn Error>> {
+        let document = bson::Document::from_reader(&mut bytes)?;
+        Ok(bson::from_document(document)?)
+    }
+}
+
 impl JobJson {
     pub fn to_json(self) -> String {
-        let mut wrapper = Vec::new();
-        wrapper.push(self);
+        let wrapper = vec![self];
         serde_json::to_string_pretty(&wrapper).unwrap()
     }
 }
@@ -110,7 +326,7 @@ pub struct AssignedClient {
 pub struct AssignedClientJson {
     #[serde(rename = "Ref")]
     pub collection: String,
-    #[serde(rename = "ID", serialize_with = "convert_oid")]
+    #[serde(rename = "ID", serialize_with = "convert_oid", deserialize_with = "parse_oid")]
     pub id: bson::oid::ObjectId,
     #[serde(rename = "DB", default, skip_serializing_if = "String::is_empty")]
     pub db: String,
@@ -122,64 +338,649 @@ impl From<AssignedClient> for AssignedClientJson {
     }
 }
 
-impl From<Client> for AssignedClient {
-    fn from(client: Client) -> Self {
-        AssignedClient {
-            collection: "clients".to_string(),
-            db: "".to_string(),
-            id: client.id.unwrap(),
-        }
+impl From<AssignedClientJson> for AssignedClient {
+    fn from(ac: AssignedClientJson) -> Self {
+        AssignedClient { id: ac.id, collection: ac.collection, db: ac.db }
     }
 }
 
+impl TryFrom<Client> for AssignedClient {
+    type Error = InfuserError;
+
+    /// References the client without a `$db`, fails if the client has no id, see `Client::to_dbref`
+    fn try_from(client: Client) -> Result<Self, Self::Error> {
+        client.to_dbref("")
+    }
+}
+
+/// Reads clients from a JSON file shaped like the `clients` collection, e.g. a `mongoexport --jsonArray` dump
+///
+/// The file holds an array of client documents with the same PascalCase field names as in the database.
+/// Extended JSON like `{"$oid": ...}` ids and `{"$date": ...}` blackout dates is understood,
+/// so exported clients can be loaded as is.
+pub fn load_clients_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<Client>, Box<dyn Error>> {
+    let json: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let clients = bson::Bson::try_from(json)?;
+    Ok(bson::from_bson(clients)?)
+}
+
+/// Parses a client or job id received as a string, e.g. from a web request
+///
+/// Surrounding whitespace is ignored, anything but 24 hex characters is rejected.
+pub fn parse_object_id(value: &str) -> Result<bson::oid::ObjectId, InfuserError> {
+    bson::oid::ObjectId::with_string(value.trim())
+        .map_err(|e| InfuserError::with_source(&format!("invalid id {:?}, expected 24 hex characters", value), e))
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
 fn convert_oid<S>(x: &bson::oid::ObjectId, s: S) -> Result<S::Ok, S::Error> where S: Serializer {
     s.serialize_str(&x.to_string())
 }
 
+fn parse_oid<'de, D>(d: D) -> Result<bson::oid::ObjectId, D::Error> where D: Deserializer<'de> {
+    let value = String::deserialize(d)?;
+    parse_object_id(&value).map_err(serde::de::Error::custom)
+}
+
+/// Merges the client's default parameters with the job's custom parameters
+///
+/// Parameters in `key=value` form are matched by key and the job's value overrides the client's,
+/// keeping the position of the client default. Any other parameter is treated as a flag and only
+/// added once. Job parameters that don't override a default are appended in their original order.
+pub fn merge_parameters(client: &Client, job: &Job) -> Vec<String> {
+    fn key(parameter: &str) -> &str {
+        parameter.split('=').next().unwrap_or(parameter)
+    }
+    let mut merged: Vec<String> = Vec::new();
+    for parameter in client.default_parameters.iter().chain(job.custom_parameters.iter()) {
+        let existing = if parameter.contains('=') {
+            merged.iter().position(|p| p.contains('=') && key(p) == key(parameter))
+        } else {
+            merged.iter().position(|p| p == parameter)
+        };
+        match existing {
+            Some(idx) => merged[idx] = parameter.to_owned(),
+            None => merged.push(parameter.to_owned()),
+        }
+    }
+    merged
+}
+
 /// loop over every client within a priority group
 ///
 /// rules: get the client...
 /// - with the lowest jobcount
-/// - that is online or has the ignore_online flag enabled
 /// - that hasn't reached its maximum job count
+/// - whose availability state is `Available`, see `Client::availability_state`
 ///
 /// Returns a tuple containing the client, the current job count and maximum job count
-pub fn get_eligible_client<'a>(grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>, ignored_clients: &Vec<Client>) -> Result<(&'a Client, i32, i32), InfuserError> {
+pub fn get_eligible_client<'a>(grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>, ignored_clients: &[Client]) -> Result<(&'a Client, i32, i32), InfuserError> {
+    get_eligible_client_at(grouped_clients, ignored_clients, &Local::now())
+}
+
+/// Same as `get_eligible_client`, but evaluates time based rules at `now` instead of the current time
+pub fn get_eligible_client_at<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    LeastLoaded.select(grouped_clients, &SelectionContext::new(ignored_clients, now))
+}
+
+/// Same as `get_eligible_client_at`, but with a ceiling on the jobs of the whole fleet
+///
+/// Once the job counts of all clients, across every priority group and including unavailable clients,
+/// add up to `global_limit`, no client is picked and the error is of kind `GlobalCapacityReached`,
+/// no matter how many free slots the clients have. Without a limit this is `get_eligible_client_at`.
+pub fn get_eligible_client_within_global_limit<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    global_limit: Option<i32>,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    if let Some(limit) = global_limit {
+        let total: i64 = grouped_clients.values().flat_map(|clients| clients.values()).map(|count| count.unwrap_or(0) as i64).sum();
+        if total >= limit as i64 {
+            return Err(InfuserError::with_kind(
+                InfuserErrorKind::GlobalCapacityReached,
+                &format!("fleet wide job limit of {} reached", limit),
+            ));
+        }
+    }
+    get_eligible_client_at(grouped_clients, ignored_clients, now)
+}
+
+/// A selection decision reported to the hook of `get_eligible_client_observed`
+#[derive(Debug)]
+pub enum SelectionEvent<'a> {
+    /// the client had the least jobs of the first priority group with an eligible client
+    Selected { client: &'a Client, current_jobs: i32, maximum_jobs: i32 },
+    /// no client was selected, `error` tells why, see `InfuserError::kind`
+    Failed { error: &'a InfuserError },
+}
+
+/// Same as `get_eligible_client_at`, but reports the outcome to `hook` before returning it
+///
+/// Lets telemetry observe every decision without threading a logger through the selection;
+/// callers that don't need it keep using `get_eligible_client_at`.
+pub fn get_eligible_client_observed<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    hook: &mut dyn FnMut(SelectionEvent),
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    let result = get_eligible_client_at(grouped_clients, ignored_clients, now);
+    match &result {
+        Ok((client, current_jobs, maximum_jobs)) => hook(SelectionEvent::Selected {
+            client,
+            current_jobs: *current_jobs,
+            maximum_jobs: *maximum_jobs,
+        }),
+        Err(error) => hook(SelectionEvent::Failed { error }),
+    }
+    result
+}
+
+/// Same as `get_eligible_client_at`, but only considers clients of the given pool
+///
+/// An empty pool considers every client. Otherwise only clients whose pool matches exactly can be
+/// picked, clients without a pool only receive jobs that don't ask for one.
+pub fn get_eligible_client_in_pool<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    pool: &str,
+    now: &DateTime<Tz>,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    select_least_loaded(grouped_clients, ignored_clients, now, |client| pool.is_empty() || client.pool == pool)
+}
+
+/// Same as `get_eligible_client_at`, but only considers the clients in `job.allowed_clients`
+///
+/// Online, capacity and priority rules apply as usual among the allowed clients.
+/// A job without allowed clients may run on any client.
+pub fn get_eligible_client_for_job<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    job: &Job,
+    now: &DateTime<Tz>,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    select_least_loaded(grouped_clients, ignored_clients, now, |client| {
+        job.allowed_clients.is_empty() || client.id.as_ref().is_some_and(|id| job.allowed_clients.contains(id))
+    })
+}
+
+/// Same as `get_eligible_client_for_job`, but avoids clients that already have a job of the job's
+/// anti affinity group, e.g. so two heavy transcodes don't share a disk
+///
+/// `client_groups` holds the anti affinity groups of the unfinished jobs per client id, as returned by
+/// `db::anti_affinity_groups`. If every eligible client has a conflicting job, a `strict` selection
+/// fails with `NoEligibleClient`, otherwise the job is placed as if it had no group.
+/// Jobs without a group are placed by `get_eligible_client_for_job` right away.
+pub fn get_eligible_client_with_anti_affinity<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    job: &Job,
+    client_groups: &HashMap<bson::oid::ObjectId, HashSet<String>>,
+    now: &DateTime<Tz>,
+    strict: bool,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    let group = match &job.anti_affinity_group {
+        Some(group) => group,
+        None => return get_eligible_client_for_job(grouped_clients, ignored_clients, job, now),
+    };
+    let conflict_free = select_least_loaded(grouped_clients, ignored_clients, now, |client| {
+        let allowed = job.allowed_clients.is_empty() || client.id.as_ref().is_some_and(|id| job.allowed_clients.contains(id));
+        let conflict = client.id.as_ref().and_then(|id| client_groups.get(id)).is_some_and(|groups| groups.contains(group));
+        allowed && !conflict
+    });
+    match conflict_free {
+        Err(e) if e.kind == InfuserErrorKind::EmptyFleet || strict => Err(e),
+        Err(_) => get_eligible_client_for_job(grouped_clients, ignored_clients, job, now),
+        ok => ok,
+    }
+}
+
+/// Same as `get_eligible_client_at`, but among equally loaded clients, clients that are actually
+/// online win over offline clients that are only eligible because of `ignore_online`
+///
+/// Without a tie, the least loaded client wins as usual, even if it is only eligible through `ignore_online`.
+pub fn get_eligible_client_preferring_online<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    select_least_loaded_by(grouped_clients, ignored_clients, now, |_| true, true)
+}
+
+/// The best eligible client of the whole fleet by strict (priority, load) order, ignoring pools
+///
+/// Priorities decide exactly like in `get_eligible_client`: only the first priority group with an
+/// eligible client is considered. The difference is the load: `get_eligible_client` picks the client
+/// with the fewest jobs, this picks the client using the smallest share of its `maximum_jobs`, so a
+/// large client with 2 of 10 slots used wins over a small one with 1 of 2 used. Equal shares go to
+/// the client with fewer jobs, then by name, so the result doesn't depend on map order.
+/// Job pools aren't taken into account, see `get_eligible_client_in_pool` to restrict them.
+pub fn best_client<'a>(grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>, ignored_clients: &[Client]) -> Result<(&'a Client, i32, i32), InfuserError> {
+    best_client_at(grouped_clients, ignored_clients, &Local::now())
+}
+
+/// Same as `best_client`, but evaluates time based rules at `now` instead of the current time
+pub fn best_client_at<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    let ignored_clients = ignore_set(ignored_clients);
+    for (_, clients) in iter_priority_groups(grouped_clients) {
+        let best = clients
+            .iter()
+            .filter(|(client, current_job_count)| is_eligible(client, **current_job_count, &ignored_clients, now))
+            .map(|(client, current_job_count)| (client, current_job_count.unwrap_or(0), client.maximum_jobs_at(now)))
+            .min_by(|a, b| {
+                // a.1 / a.2 < b.1 / b.2 without dividing by a zero maximum, a client without jobs has no load
+                let share = |count: i32, maximum: i32| (count as i64, maximum.max(1) as i64);
+                let ((count_a, max_a), (count_b, max_b)) = (share(a.1, a.2), share(b.1, b.2));
+                (count_a * max_b).cmp(&(count_b * max_a)).then_with(|| a.1.cmp(&b.1)).then_with(|| a.0.name.cmp(&b.0.name))
+            });
+        if let Some(best) = best {
+            return Ok(best);
+        }
+    }
+    Err(no_client_error(grouped_clients))
+}
+
+/// The selection loop behind `get_eligible_client` and `strategy::LeastLoaded`, restricted to the clients accepted by `filter`
+pub(crate) fn select_least_loaded<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    filter: impl Fn(&Client) -> bool,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    select_least_loaded_by(grouped_clients, ignored_clients, now, filter, false)
+}
+
+fn select_least_loaded_by<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    filter: impl Fn(&Client) -> bool,
+    prefer_online: bool,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    let ignored_clients = ignore_set(ignored_clients);
     // loop over priority group
-    for (_, clients) in grouped_clients {
+    for (_, clients) in iter_priority_groups(grouped_clients) {
         let mut eligible_job_count = i32::MAX;
         let mut eligible: Option<&Client> = None;
         // loop over clients in priority group
         for (client, current_job_count) in clients {
-            if ignored_clients.iter().any(|c| c == client) {
-                continue;
-            }
-            if !client.online && !client.ignore_online {
+            if !filter(client) || !is_eligible(client, *current_job_count, &ignored_clients, now) {
                 continue;
             }
-            if let Some(count) = current_job_count {
-                if *count < eligible_job_count && *count < client.maximum_jobs {
-                    eligible = Some(client);
-                    eligible_job_count = *count;
-                }
-            } else {
+            let count = current_job_count.unwrap_or(0);
+            let replaces = match eligible {
+                None => true,
+                Some(_) if count != eligible_job_count => count < eligible_job_count,
+                // tie
+                Some(best) if prefer_online => client.online && !best.online,
+                Some(_) => current_job_count.is_none(),
+            };
+            if replaces {
                 eligible = Some(client);
-                eligible_job_count = 0;
+                eligible_job_count = count;
             }
         }
         // if a client was found within the priority group,
         // return it, otherwise move on to the next one
-        match eligible {
-            Some(client) => {
-                return Ok((client, eligible_job_count, client.maximum_jobs));
-            }
-            None => (),
+        if let Some(client) = eligible {
+            return Ok((client, eligible_job_count, client.maximum_jobs_at(now)));
         }
     }
     // if no client has been found, return an error
-    Err(InfuserError {
-        message: "no eligible client found".to_string(),
-    })
+    Err(no_client_error(grouped_clients))
+}
+
+/// Picks the client with the least estimated minutes of work from the first priority group
+/// that has room for `job`
+///
+/// Clients with a `maximum_minutes` budget are capped by the summed estimated minutes of their running and queued jobs:
+/// they are eligible as long as their current load plus the job's estimate fits the budget, their
+/// `maximum_jobs` is not consulted. Clients without a budget fall back to the count based cap of
+/// `get_eligible_client`. Either way, the client with the lowest load in minutes wins.
+/// Jobs without an estimate count as zero minutes.
+///
+/// ### Parameters:
+/// - load_minutes: the load per client id, as returned by `db::get_machine_load_minutes`
+///
+/// Returns a tuple containing the client and its current load in minutes
+pub fn get_eligible_client_by_minutes<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    load_minutes: &HashMap<String, i32>,
+    job: &Job,
+    now: &DateTime<Tz>,
+) -> Result<(&'a Client, i32), InfuserError> {
+    let job_minutes = job.estimated_minutes.unwrap_or(0);
+    let ignored_clients = ignore_set(ignored_clients);
+    for (_, clients) in iter_priority_groups(grouped_clients) {
+        let mut eligible: Option<(&Client, i32)> = None;
+        for (client, current_job_count) in clients {
+            if !is_selectable(client, &ignored_clients, now) {
+                continue;
+            }
+            let client_id = client.id.to_owned().unwrap_or_default().to_string();
+            let load = load_minutes.get(&client_id).copied().unwrap_or(0);
+            let fits = match client.maximum_minutes {
+                Some(budget) => load + job_minutes <= budget,
+                None => current_job_count.is_none_or(|count| count < client.maximum_jobs_at(now)),
+            };
+            if fits && eligible.is_none_or(|(_, lowest)| load < lowest) {
+                eligible = Some((client, load));
+            }
+        }
+        if let Some(found) = eligible {
+            return Ok(found);
+        }
+    }
+    Err(no_client_error(grouped_clients))
+}
+
+/// The rotation cursors of `get_round_robin_client`, one per priority group
+///
+/// Persist it with `db::save_scheduler_state` so the rotation continues where it left off after a restart.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct RoundRobinState {
+    #[serde(default)]
+    pub cursors: Vec<GroupCursor>,
+}
+
+/// The client a priority group handed its last job to
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct GroupCursor {
+    pub priority: i32,
+    /// the client's id as a string, or its name if it has no id
+    pub last_client: String,
+}
+
+impl RoundRobinState {
+    fn cursor(&self, priority: i32) -> Option<&str> {
+        self.cursors.iter().find(|cursor| cursor.priority == priority).map(|cursor| cursor.last_client.as_str())
+    }
+
+    fn advance(&mut self, priority: i32, client: String) {
+        match self.cursors.iter_mut().find(|cursor| cursor.priority == priority) {
+            Some(cursor) => cursor.last_client = client,
+            None => self.cursors.push(GroupCursor { priority, last_client: client }),
+        }
+    }
+}
+
+/// Hands out jobs in turn to the eligible clients of the first priority group that has one
+///
+/// Instead of the least loaded client, the next eligible client after the one that got the previous
+/// job of the group is picked, ordered by id, so every client gets the same share of jobs regardless
+/// of how fast it finishes them. Clients that were removed or are ineligible are skipped, the
+/// rotation goes on with the next client in order. `state` is updated with the pick.
+///
+/// Returns a tuple containing the client, the current job count and maximum job count
+pub fn get_round_robin_client<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    state: &mut RoundRobinState,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    let ignored_clients = ignore_set(ignored_clients);
+    let rotation_key = |client: &Client| match &client.id {
+        Some(id) => id.to_string(),
+        None => client.name.to_owned(),
+    };
+    for (prio, clients) in iter_priority_groups(grouped_clients) {
+        let mut candidates: Vec<(String, &Client, i32)> = clients
+            .iter()
+            .filter(|(client, current_job_count)| is_eligible(client, **current_job_count, &ignored_clients, now))
+            .map(|(client, current_job_count)| (rotation_key(client), client, current_job_count.unwrap_or(0)))
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        candidates.sort_by(|a, b| a.0.cmp(&b.0));
+        let next = match state.cursor(prio) {
+            Some(last) => candidates.iter().position(|(key, _, _)| key.as_str() > last).unwrap_or(0),
+            None => 0,
+        };
+        let (key, client, current_jobs) = candidates.swap_remove(next);
+        state.advance(prio, key);
+        return Ok((client, current_jobs, client.maximum_jobs_at(now)));
+    }
+    Err(no_client_error(grouped_clients))
+}
+
+/// Picks a random client from the first priority group that has an eligible client
+///
+/// Unlike `get_eligible_client`, which always picks the least loaded client, every eligible client
+/// may be chosen with a probability proportional to its remaining capacity (`maximum_jobs - current`).
+/// This spreads jobs when several schedulers run at once and would otherwise all pick the same machine.
+/// Clients without free slots are never picked. Pass a seeded rng to get reproducible picks.
+///
+/// Returns a tuple containing the client, the current job count and maximum job count
+pub fn get_weighted_random_client<'a, Tz: TimeZone, R: Rng + ?Sized>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    rng: &mut R,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    if let Some(candidates) = weighted_candidates(grouped_clients, ignored_clients, now) {
+        let total: i64 = candidates.iter().map(|(_, _, weight)| weight).sum();
+        let mut pick = rng.gen_range(0..total);
+        for (client, count, weight) in candidates {
+            if pick < weight {
+                return Ok((client, count, client.maximum_jobs_at(now)));
+            }
+            pick -= weight;
+        }
+    }
+    Err(no_client_error(grouped_clients))
+}
+
+/// The probability of every client to be picked by `get_weighted_random_client`, by client name
+///
+/// Within the first priority group with a free slot the probabilities are the clients' remaining
+/// capacity normalized to sum up to 1. All other clients, including the ineligible ones in that
+/// group, have a probability of 0. Without any free slot every client has a probability of 0.
+pub fn selection_probabilities<Tz: TimeZone>(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+) -> HashMap<String, f64> {
+    let mut probabilities: HashMap<String, f64> = grouped_clients
+        .values()
+        .flat_map(|clients| clients.keys())
+        .map(|client| (client.name.to_owned(), 0.0))
+        .collect();
+    if let Some(candidates) = weighted_candidates(grouped_clients, ignored_clients, now) {
+        let total: i64 = candidates.iter().map(|(_, _, weight)| weight).sum();
+        for (client, _, weight) in candidates {
+            probabilities.insert(client.name.to_owned(), weight as f64 / total as f64);
+        }
+    }
+    probabilities
+}
+
+/// The eligible clients of the first priority group with a free slot, weighted by their remaining
+/// capacity and sorted by name and id, as a tuple of client, current job count and weight
+fn weighted_candidates<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+) -> Option<Vec<(&'a Client, i32, i64)>> {
+    let ignored_clients = ignore_set(ignored_clients);
+    for (_, clients) in iter_priority_groups(grouped_clients) {
+        let mut candidates: Vec<(&Client, i32, i64)> = clients
+            .iter()
+            .filter(|(client, _)| is_selectable(client, &ignored_clients, now))
+            .map(|(client, count)| (client, count.unwrap_or(0), remaining_capacity(client, *count, now) as i64))
+            .filter(|(_, _, remaining)| *remaining > 0)
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        // map iteration order is random, sort so that a seeded rng picks the same client every time
+        candidates.sort_by(|a, b| a.0.name.cmp(&b.0.name).then_with(|| a.0.id.cmp(&b.0.id)));
+        return Some(candidates);
+    }
+    None
+}
+
+/// Picks a client across all priority groups, treating priority as a weight instead of a strict order
+///
+/// Every eligible client with a free slot competes. A client scores `weight(rank)` times the share
+/// of its slots that is still free, `rank` being the position of its priority group (0 for the
+/// highest priority). The highest score wins, ties go to the higher priority group.
+/// So a lower priority client gets the job once it has enough room to make up for its lower weight,
+/// unlike `get_eligible_client`, which never looks past the first group with room.
+/// See `priority_weight` for a weight based on an exponent.
+///
+/// Returns a tuple containing the client, the current job count and maximum job count
+pub fn get_soft_priority_client<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    ignored_clients: &[Client],
+    now: &DateTime<Tz>,
+    weight: impl Fn(usize) -> f64,
+) -> Result<(&'a Client, i32, i32), InfuserError> {
+    let ignored_clients = ignore_set(ignored_clients);
+    let mut best: Option<(&Client, i32, f64)> = None;
+    for (rank, (_, clients)) in iter_priority_groups(grouped_clients).enumerate() {
+        let group_weight = weight(rank);
+        let mut candidates: Vec<(&Client, &Option<i32>)> = clients.iter().collect();
+        // map iteration order is random, sort so that ties are broken the same way every time
+        candidates.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        for (client, current_job_count) in candidates {
+            let maximum_jobs = client.maximum_jobs_at(now);
+            let remaining = remaining_capacity(client, *current_job_count, now);
+            if !is_selectable(client, &ignored_clients, now) || remaining <= 0 || maximum_jobs <= 0 {
+                continue;
+            }
+            let score = group_weight * remaining as f64 / maximum_jobs as f64;
+            if best.is_none_or(|(_, _, best_score)| score > best_score) {
+                best = Some((client, current_job_count.unwrap_or(0), score));
+            }
+        }
+    }
+    match best {
+        Some((client, count, _)) => Ok((client, count, client.maximum_jobs_at(now))),
+        None => Err(no_client_error(grouped_clients)),
+    }
+}
+
+/// A weight for `get_soft_priority_client`: `1 / (rank + 1) ^ exponent`
+///
+/// An exponent of 0 ignores priorities, the larger it is the closer selection gets to strict groups.
+pub fn priority_weight(exponent: f64) -> impl Fn(usize) -> f64 {
+    move |rank| 1.0 / (rank as f64 + 1.0).powf(exponent)
+}
+
+/// The error returned when selection found no client, telling an empty fleet apart from a saturated one
+fn no_client_error(grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>) -> InfuserError {
+    if grouped_clients.values().all(|clients| clients.is_empty()) {
+        InfuserError::with_kind(InfuserErrorKind::EmptyFleet, "no clients in fleet")
+    } else {
+        InfuserError::with_kind(InfuserErrorKind::NoEligibleClient, "no eligible client found")
+    }
+}
+
+/// Finds the client with the most free slots across all priority groups
+///
+/// This answers "where is there the most room overall" and is not a scheduling decision:
+/// `get_eligible_client` never looks past the first priority group with an eligible client,
+/// while this function ignores priorities entirely and may return a client that
+/// `get_eligible_client` would only pick once every higher priority group is full.
+/// Ties are broken in favor of the higher priority group.
+///
+/// Returns the client and its remaining capacity, or `None` if no eligible client has a free slot
+pub fn least_loaded_client<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> Option<(&'a Client, i32)> {
+    let mut least_loaded: Option<(&Client, i32)> = None;
+    for (_, clients) in iter_priority_groups(grouped_clients) {
+        for (client, current_job_count) in clients {
+            if !is_selectable(client, &HashSet::new(), now) {
+                continue;
+            }
+            let remaining = remaining_capacity(client, *current_job_count, now);
+            if remaining > 0 && least_loaded.is_none_or(|(_, most)| remaining > most) {
+                least_loaded = Some((client, remaining));
+            }
+        }
+    }
+    least_loaded
+}
+
+/// The clients with a free slot at `now`, most remaining capacity first, e.g. for a dashboard
+///
+/// Applies the same availability rules as `get_eligible_client`, clients that couldn't take a job
+/// right now are left out. Like `least_loaded_client` this ignores priorities for the order,
+/// equal capacity is sorted by priority and then by name.
+pub fn clients_by_free_capacity<'a, Tz: TimeZone>(
+    grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+    now: &DateTime<Tz>,
+) -> Vec<(&'a Client, i32)> {
+    let mut clients: Vec<(&Client, i32)> = grouped_clients
+        .values()
+        .flatten()
+        .filter(|(client, current_job_count)| is_eligible(client, **current_job_count, &HashSet::new(), now))
+        .map(|(client, current_job_count)| (client, remaining_capacity(client, *current_job_count, now)))
+        .filter(|(_, remaining)| *remaining > 0)
+        .collect();
+    clients.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.priority.cmp(&b.0.priority)).then_with(|| a.0.name.cmp(&b.0.name)));
+    clients
+}
+
+/// Checks whether at least one client could accept a job at `now`
+///
+/// Applies exactly the rules of `get_eligible_client`: online state, capacity,
+/// blackout periods and daily availability windows.
+pub fn can_schedule_now<Tz: TimeZone>(grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>, now: &DateTime<Tz>) -> bool {
+    grouped_clients
+        .values()
+        .flatten()
+        .any(|(client, current_job_count)| is_eligible(client, *current_job_count, &HashSet::new(), now))
+}
+
+/// The ignore list as a set, so that checking a client is O(1) instead of a scan of the list
+///
+/// Build it once per selection, not per client. Clients are matched like `Client`'s `PartialEq` does,
+/// by id or, for clients without an id, by name.
+pub(crate) fn ignore_set(ignored_clients: &[Client]) -> HashSet<&Client> {
+    ignored_clients.iter().collect()
+}
+
+/// Checks the rules every selection mode applies before looking at job counts:
+/// the client isn't ignored and is available
+pub(crate) fn is_selectable<Tz: TimeZone>(client: &Client, ignored_clients: &HashSet<&Client>, now: &DateTime<Tz>) -> bool {
+    if ignored_clients.contains(client) {
+        return false;
+    }
+    client.availability_state(now) == AvailabilityState::Available
+}
+
+/// Checks whether `get_eligible_client` may pick the client,
+/// a client without a job count is eligible regardless of its maximum job count
+pub(crate) fn is_eligible<Tz: TimeZone>(client: &Client, current_job_count: Option<i32>, ignored_clients: &HashSet<&Client>, now: &DateTime<Tz>) -> bool {
+    is_selectable(client, ignored_clients, now) && current_job_count.is_none_or(|count| count < client.maximum_jobs_at(now))
+}
+
+/// Free job slots of a client at `now`, a client without a job count has no jobs assigned
+pub(crate) fn remaining_capacity<Tz: TimeZone>(client: &Client, current_job_count: Option<i32>, now: &DateTime<Tz>) -> i32 {
+    client.maximum_jobs_at(now) - current_job_count.unwrap_or(0)
 }
 
 pub fn group_clients(client_vec: Vec<Client>, machine_jobcounts: HashMap<String, i32>) -> BTreeMap<i32, HashMap<Client, Option<i32>>> {
@@ -187,7 +988,7 @@ pub fn group_clients(client_vec: Vec<Client>, machine_jobcounts: HashMap<String,
     for client in client_vec {
         let prio = client.priority;
         let client_id_string = client.id.to_owned().unwrap_or_default().to_string();
-        let job_count = machine_jobcounts.get(&client_id_string).and_then(|count| Some(count.to_owned()));
+        let job_count = machine_jobcounts.get(&client_id_string).copied();
         dict.entry(prio).or_insert(HashMap::new()).insert(client, job_count);
         /*
         match dict.entry(prio) {
@@ -199,28 +1000,557 @@ pub fn group_clients(client_vec: Vec<Client>, machine_jobcounts: HashMap<String,
     dict
 }
 
+/// Iterates over the priority groups in scheduling order
+///
+/// Lower priority values are scheduled first, so the group with the smallest value comes first.
+pub fn iter_priority_groups(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+) -> impl Iterator<Item = (i32, &HashMap<Client, Option<i32>>)> {
+    grouped_clients.iter().map(|(prio, clients)| (*prio, clients))
+}
+
+/// Returns the priority group that is considered first during scheduling, if there is any
+pub fn highest_priority_group(
+    grouped_clients: &BTreeMap<i32, HashMap<Client, Option<i32>>>,
+) -> Option<(i32, &HashMap<Client, Option<i32>>)> {
+    iter_priority_groups(grouped_clients).next()
+}
+
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "mongodb")]
     use crate::db;
+    use crate::*;
     use std::error::Error;
 
+    fn client(name: &str, priority: i32, maximum_jobs: i32, online: bool) -> Client {
+        Client {
+            id: Some(bson::oid::ObjectId::new()),
+            name: name.to_string(),
+            availability_start: String::new(),
+            availability_end: String::new(),
+            maximum_jobs,
+            priority,
+            online,
+            ..Default::default()
+        }
+    }
+
+    fn job_counts(counts: &[(&Client, i32)]) -> HashMap<String, i32> {
+        counts.iter().map(|(client, count)| (client.id.as_ref().unwrap().to_string(), *count)).collect()
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
 
     #[test]
+    fn test_priority_group_order() {
+        let clients = vec![client("c", 3, 1, true), client("a", 1, 1, true), client("b", 2, 1, true)];
+        let grouped = group_clients(clients, HashMap::new());
+        let order: Vec<i32> = iter_priority_groups(&grouped).map(|(prio, _)| prio).collect();
+        assert_eq!(order, vec![1, 2, 3]);
+        let (prio, group) = highest_priority_group(&grouped).unwrap();
+        assert_eq!(prio, 1);
+        assert_eq!(group.keys().next().unwrap().name, "a");
+        assert!(highest_priority_group(&BTreeMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_blackout() {
+        let now = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let mut blacked_out = client("blacked_out", 1, 2, true);
+        blacked_out.blackout_start = Some((now - chrono::Duration::hours(1)).into());
+        blacked_out.blackout_end = Some((now + chrono::Duration::hours(1)).into());
+        assert!(blacked_out.in_blackout(&now));
+        assert!(!blacked_out.in_blackout(&(now + chrono::Duration::hours(1))));
+        assert!(!blacked_out.in_blackout(&(now - chrono::Duration::hours(2))));
+
+        let mut open_ended = client("open_ended", 1, 2, true);
+        open_ended.blackout_start = Some((now - chrono::Duration::hours(1)).into());
+        assert!(open_ended.in_blackout(&now));
+        assert!(!client("regular", 1, 2, true).in_blackout(&now));
+
+        let fallback = client("fallback", 2, 2, true);
+        let grouped = group_clients(vec![blacked_out, fallback], HashMap::new());
+        let (chosen, _, _) = get_eligible_client_at(&grouped, &[], &now).unwrap();
+        assert_eq!(chosen.name, "fallback");
+    }
+
+    #[test]
+    fn test_weighted_random_client() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let now = Local::now();
+        let full = client("full", 1, 2, true);
+        let roomy = client("roomy", 1, 4, true);
+        let small = client("small", 1, 1, true);
+        let backup = client("backup", 2, 10, true);
+        let counts = job_counts(&[(&full, 2), (&roomy, 1)]);
+        let grouped = group_clients(vec![full, roomy, small, backup], counts);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut picks: HashMap<String, i32> = HashMap::new();
+        for _ in 0..1000 {
+            let (chosen, _, _) = get_weighted_random_client(&grouped, &[], &now, &mut rng).unwrap();
+            *picks.entry(chosen.name.to_owned()).or_insert(0) += 1;
+        }
+        // roomy has 3 free slots, small has 1, full and the lower priority backup have to be skipped
+        assert_eq!(picks.len(), 2);
+        assert!(picks["roomy"] > 2 * picks["small"]);
+
+        let first = get_weighted_random_client(&grouped, &[], &now, &mut StdRng::seed_from_u64(7)).unwrap().0;
+        let second = get_weighted_random_client(&grouped, &[], &now, &mut StdRng::seed_from_u64(7)).unwrap().0;
+        assert_eq!(first.name, second.name);
+    }
+
+    #[test]
+    fn test_job_bson_bytes() -> Result<(), Box<dyn Error>> {
+        let job = Job {
+            id: Some(bson::oid::ObjectId::new()),
+            name: "name".to_string(),
+            path: "\\\\server\\share\\file.ts".to_string(),
+            subtitle: "subtitle".to_string(),
+            custom_parameters: vec!["-preset slow".to_string()],
+            assigned_client: AssignedClient {
+                collection: "clients".to_string(),
+                id: bson::oid::ObjectId::new(),
+                db: "fleet".to_string(),
+            },
+            ..Default::default()
+        };
+        let decoded = Job::from_bson_bytes(&job.to_bson_bytes()?)?;
+        assert_eq!(decoded.id, job.id);
+        assert_eq!(decoded.path, job.path);
+        assert_eq!(decoded.custom_parameters, job.custom_parameters);
+        assert_eq!(decoded.assigned_client.collection, "clients");
+        assert_eq!(decoded.assigned_client.id, job.assigned_client.id);
+        assert_eq!(decoded.assigned_client.db, "fleet");
+        assert!(Job::from_bson_bytes(&[1, 2, 3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_selection_probabilities() {
+        let now = Local::now();
+        let roomy = client("roomy", 1, 4, true);
+        let tight = client("tight", 1, 2, true);
+        let offline = client("offline", 1, 4, false);
+        let backup = client("backup", 2, 4, true);
+        let grouped = group_clients(vec![roomy.clone(), tight.clone(), offline, backup], job_counts(&[(&roomy, 1), (&tight, 1)]));
+        let probabilities = selection_probabilities(&grouped, &[], &now);
+        assert_eq!(probabilities["roomy"], 0.75);
+        assert_eq!(probabilities["tight"], 0.25);
+        assert_eq!(probabilities["offline"], 0.0);
+        assert_eq!(probabilities["backup"], 0.0);
+    }
+
+    #[test]
+    fn test_least_loaded_client() {
+        let now = Local::now();
+        let busy = client("busy", 1, 4, true);
+        let idle = client("idle", 1, 1, true);
+        let big = client("big", 2, 8, true);
+        let offline = client("offline", 3, 20, false);
+        let counts = job_counts(&[(&busy, 1), (&big, 2)]);
+        let grouped = group_clients(vec![busy, idle, big, offline], counts);
+
+        let (chosen, remaining) = least_loaded_client(&grouped, &now).unwrap();
+        assert_eq!(chosen.name, "big");
+        assert_eq!(remaining, 6);
+        // the regular selection sticks to the first priority group
+        assert_eq!(get_eligible_client_at(&grouped, &[], &now).unwrap().0.name, "idle");
+        assert!(least_loaded_client(&BTreeMap::new(), &now).is_none());
+    }
+
+    #[test]
+    fn test_can_schedule_now() {
+        let now = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let full = client("full", 1, 1, true);
+        let offline = client("offline", 1, 1, false);
+        let mut night_shift = client("night_shift", 2, 1, true);
+        night_shift.availability_start = "22:00".to_string();
+        night_shift.availability_end = "06:00".to_string();
+        let counts = job_counts(&[(&full, 1)]);
+        let grouped = group_clients(vec![full, offline, night_shift], counts);
+        assert!(!can_schedule_now(&grouped, &now));
+        assert!(get_eligible_client_at(&grouped, &[], &now).is_err());
+
+        let midnight = Utc.with_ymd_and_hms(2021, 5, 2, 0, 0, 0).unwrap();
+        assert!(can_schedule_now(&grouped, &midnight));
+        assert_eq!(get_eligible_client_at(&grouped, &[], &midnight).unwrap().0.name, "night_shift");
+    }
+
+    #[test]
+    fn test_ignore_availability() {
+        let noon = Utc.with_ymd_and_hms(2021, 5, 1, 12, 0, 0).unwrap();
+        let mut reference = client("reference", 1, 1, false);
+        reference.availability_start = "22:00".to_string();
+        reference.availability_end = "06:00".to_string();
+        reference.ignore_online = true;
+        let fallback = client("fallback", 2, 1, true);
+        let grouped = group_clients(vec![reference.clone(), fallback.clone()], HashMap::new());
+        assert_eq!(get_eligible_client_at(&grouped, &[], &noon).unwrap().0.name, "fallback");
+
+        reference.ignore_availability = true;
+        let grouped = group_clients(vec![reference, fallback], HashMap::new());
+        assert_eq!(get_eligible_client_at(&grouped, &[], &noon).unwrap().0.name, "reference");
+    }
+
+    #[test]
+    fn test_prefer_online_on_tie() {
+        let now = Utc::now();
+        let online = client("online", 1, 2, true);
+        let mut forced = client("forced", 1, 2, false);
+        forced.ignore_online = true;
+        for clients in [vec![online.clone(), forced.clone()], vec![forced.clone(), online.clone()]].iter() {
+            let grouped = group_clients(clients.clone(), job_counts(&[(&online, 0), (&forced, 0)]));
+            assert_eq!(get_eligible_client_preferring_online(&grouped, &[], &now).unwrap().0.name, "online");
+        }
+        // no tie, the least loaded client still wins
+        let grouped = group_clients(vec![online.clone(), forced.clone()], job_counts(&[(&online, 1)]));
+        assert_eq!(get_eligible_client_preferring_online(&grouped, &[], &now).unwrap().0.name, "forced");
+    }
+
+    #[test]
+    fn test_large_ignore_list() {
+        let now = Utc::now();
+        let clients: Vec<Client> = (0..5000).map(|i| client(&format!("client{}", i), 1, 1, true)).collect();
+        let (ignored, kept) = clients.split_at(4999);
+        let grouped = group_clients(clients.clone(), HashMap::new());
+        assert_eq!(get_eligible_client_at(&grouped, ignored, &now).unwrap().0.name, kept[0].name);
+        assert!(get_eligible_client_at(&grouped, &clients, &now).is_err());
+
+        // clients without an id are still matched by name
+        let unnamed = Client { id: None, ..client("unnamed", 1, 1, true) };
+        let grouped = group_clients(vec![unnamed.clone(), kept[0].clone()], HashMap::new());
+        assert_eq!(get_eligible_client_at(&grouped, &[unnamed], &now).unwrap().0.name, kept[0].name);
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let now = Utc::now();
+        let busy = client("busy", 1, 4, true);
+        let idle = client("idle", 2, 4, true);
+        let grouped = group_clients(vec![busy.clone(), idle.clone()], job_counts(&[(&busy, 3), (&idle, 1)]));
+        assert_eq!(get_eligible_client_within_global_limit(&grouped, &[], &now, Some(5)).unwrap().0.name, "busy");
+        let err = get_eligible_client_within_global_limit(&grouped, &[], &now, Some(4)).unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::GlobalCapacityReached);
+        assert!(get_eligible_client_within_global_limit(&grouped, &[], &now, None).is_ok());
+    }
+
+    #[test]
+    fn test_clients_by_free_capacity() {
+        let now = Utc::now();
+        let roomy = client("roomy", 2, 8, true);
+        let small = client("small", 1, 3, true);
+        let equal = client("equal", 2, 3, true);
+        let full = client("full", 1, 2, true);
+        let offline = client("offline", 1, 10, false);
+        let counts = job_counts(&[(&roomy, 2), (&full, 2)]);
+        let grouped = group_clients(vec![roomy, small, equal, full, offline], counts);
+        let ranking: Vec<(&str, i32)> = clients_by_free_capacity(&grouped, &now).iter().map(|(c, free)| (c.name.as_str(), *free)).collect();
+        assert_eq!(ranking, vec![("roomy", 6), ("small", 3), ("equal", 3)]);
+    }
+
+    #[test]
+    fn test_to_dbref() {
+        let stored = client("stored", 1, 1, true);
+        let dbref = stored.to_dbref("fleet").unwrap();
+        assert_eq!((dbref.collection.as_str(), dbref.db.as_str()), ("clients", "fleet"));
+        assert_eq!(Some(dbref.id), stored.id);
+
+        assert_eq!(AssignedClient::try_from(stored.clone()).unwrap().db, "");
+
+        let unsaved = Client { id: None, ..stored };
+        let err = unsaved.to_dbref("").unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::NotFound);
+        assert_eq!(err.to_string(), "client stored has no id and can't be referenced");
+        assert_eq!(AssignedClient::try_from(unsaved).unwrap_err().kind, InfuserErrorKind::NotFound);
+    }
+
+    #[test]
+    #[cfg(feature = "field-aliases")]
+    fn test_job_field_aliases() -> Result<(), Box<dyn Error>> {
+        let document = bson::doc! {
+            "Title": "Wildblumen", "FilePath": "D:/Rec/wildblumen.ts", "EpisodeTitle": "Wald",
+            "AssignedClient": { "$ref": "clients", "$id": bson::oid::ObjectId::new() },
+        };
+        let job: Job = bson::from_document(document)?;
+        assert_eq!((job.name.as_str(), job.path.as_str(), job.subtitle.as_str()), ("Wildblumen", "D:/Rec/wildblumen.ts", "Wald"));
+        let written = bson::to_document(&job)?;
+        assert!(written.contains_key("Name") && !written.contains_key("Title"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_best_client() {
+        let now = Utc::now();
+        let large = client("large", 1, 10, true);
+        let small = client("small", 1, 2, true);
+        let backup = client("backup", 2, 10, true);
+        let grouped = group_clients(vec![large.clone(), small.clone(), backup], job_counts(&[(&large, 2), (&small, 1)]));
+        // fewest jobs vs smallest share of the capacity
+        assert_eq!(get_eligible_client_at(&grouped, &[], &now).unwrap().0.name, "small");
+        assert_eq!(best_client_at(&grouped, &[], &now).unwrap(), (&large, 2, 10));
+        // the priority still comes first, even though backup has no jobs at all
+        let grouped = group_clients(vec![large.clone(), client("backup", 2, 10, true)], job_counts(&[(&large, 9)]));
+        assert_eq!(best_client_at(&grouped, &[], &now).unwrap().0.name, "large");
+        assert_eq!(best_client_at(&grouped, &[large], &now).unwrap().0.name, "backup");
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let now = Utc::now();
+        let clients = vec![client("a", 1, 5, true), client("b", 1, 5, true), client("c", 1, 5, true)];
+        let grouped = group_clients(clients.clone(), job_counts(&[(&clients[0], 4)]));
+        let mut state = RoundRobinState::default();
+        let picks: Vec<String> = (0..4).map(|_| get_round_robin_client(&grouped, &[], &now, &mut state).unwrap().0.name.to_owned()).collect();
+        // ids are created in order, so the rotation is a, b, c and wraps around regardless of load
+        assert_eq!(picks, vec!["a", "b", "c", "a"]);
+
+        // a restored state continues the rotation, skipping ignored clients
+        let mut restored: RoundRobinState = bson::from_document(bson::to_document(&state).unwrap()).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(get_round_robin_client(&grouped, &clients[1..2], &now, &mut restored).unwrap().0.name, "c");
+    }
+
+    #[test]
+    fn test_anti_affinity() {
+        let now = Utc::now();
+        let busy = client("busy", 1, 4, true);
+        let other = client("other", 2, 4, true);
+        let grouped = group_clients(vec![busy.clone(), other.clone()], job_counts(&[(&busy, 1)]));
+        let job = Job { anti_affinity_group: Some("transcode".to_string()), ..Default::default() };
+        let mut client_groups = HashMap::new();
+        client_groups.insert(busy.id.clone().unwrap(), vec!["transcode".to_string()].into_iter().collect::<HashSet<String>>());
+        let pick = |job: &Job, strict| get_eligible_client_with_anti_affinity(&grouped, &[], job, &client_groups, &now, strict).map(|(c, _, _)| c.name.to_owned());
+        // the higher priority client already runs a transcode
+        assert_eq!(pick(&job, true).unwrap(), "other");
+        assert_eq!(pick(&Job::default(), true).unwrap(), "busy");
+
+        let pinned = Job { allowed_clients: vec![busy.id.unwrap()], ..job };
+        assert_eq!(pick(&pinned, true).unwrap_err().kind, InfuserErrorKind::NoEligibleClient);
+        assert_eq!(pick(&pinned, false).unwrap(), "busy");
+    }
+
+    #[test]
+    fn test_assigned_client_json_round_trip() -> Result<(), Box<dyn Error>> {
+        let dbref = AssignedClient { collection: "clients".to_string(), id: bson::oid::ObjectId::new(), db: "fleet".to_string() };
+        let json = serde_json::to_string(&AssignedClientJson::from(dbref.clone()))?;
+        assert!(json.contains(&format!("\"ID\":\"{}\"", dbref.id)));
+        let parsed: AssignedClientJson = serde_json::from_str(&json)?;
+        let restored = AssignedClient::from(parsed);
+        assert_eq!((restored.collection, restored.id, restored.db), (dbref.collection, dbref.id, dbref.db));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_parameters() {
+        let mut defaults = client("defaults", 1, 1, true);
+        defaults.default_parameters = vec!["codec=hevc".to_string(), "-hwaccel".to_string(), "preset=slow".to_string()];
+        let job = Job {
+            custom_parameters: vec!["preset=fast".to_string(), "-hwaccel".to_string(), "crf=20".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(merge_parameters(&defaults, &job), vec!["codec=hevc", "-hwaccel", "preset=fast", "crf=20"]);
+        assert_eq!(merge_parameters(&client("plain", 1, 1, true), &job), job.custom_parameters);
+    }
+
+    #[test]
+    fn test_cancel_requested_serialization() -> Result<(), Box<dyn Error>> {
+        let job = Job { name: "job".to_string(), ..Default::default() };
+        assert!(!bson::to_document(&job)?.contains_key("CancelRequested"));
+        assert!(!JobJson::from(job.clone()).to_json().contains("CancelRequested"));
+
+        let cancelled = Job { cancel_requested: true, ..job };
+        assert!(bson::to_document(&cancelled)?.get_bool("CancelRequested")?);
+        assert!(JobJson::from(cancelled).to_json().contains("\"CancelRequested\": true"));
+
+        let legacy: Job = bson::from_document(bson::to_document(&Job::default())?)?;
+        assert!(!legacy.cancel_requested);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_isolation() {
+        let now = Local::now();
+        let fast = Client { pool: "fast".to_string(), ..client("fast", 1, 4, true) };
+        let archival = Client { pool: "archival".to_string(), ..client("archival", 2, 4, true) };
+        let shared = client("shared", 3, 4, true);
+        let counts = job_counts(&[(&archival, 4)]);
+        let grouped = group_clients(vec![fast, archival, shared], counts);
+
+        assert_eq!(get_eligible_client_in_pool(&grouped, &[], "fast", &now).unwrap().0.name, "fast");
+        // the archival pool is full, jobs for it must not spill into other pools
+        assert!(get_eligible_client_in_pool(&grouped, &[], "archival", &now).is_err());
+        assert!(get_eligible_client_in_pool(&grouped, &[], "unknown", &now).is_err());
+        // without a pool every client is considered
+        assert_eq!(get_eligible_client_in_pool(&grouped, &[], "", &now).unwrap().0.name, "fast");
+        let ignored = vec![grouped[&1].keys().next().unwrap().to_owned()];
+        assert_eq!(get_eligible_client_in_pool(&grouped, &ignored, "", &now).unwrap().0.name, "shared");
+    }
+
+    #[test]
+    fn test_parse_object_id() {
+        let id = bson::oid::ObjectId::new();
+        assert_eq!(parse_object_id(&id.to_hex()).unwrap(), id);
+        assert_eq!(parse_object_id(&format!(" {} ", id)).unwrap(), id);
+        let err = parse_object_id("not-an-id").unwrap_err();
+        assert_eq!(err.message, "invalid id \"not-an-id\", expected 24 hex characters");
+        assert!(err.source().is_some());
+        assert!(parse_object_id("").is_err());
+    }
+
+    #[test]
+    fn test_capacity_schedule_selection() {
+        let night = availability::CapacityOverride { start: "22:00".to_string(), end: "06:00".to_string(), maximum_jobs: 2 };
+        let worker = Client { capacity_schedule: vec![night], ..client("worker", 1, 1, true) };
+        let grouped = group_clients(vec![worker.clone()], job_counts(&[(&worker, 1)]));
+        let evening = Utc.with_ymd_and_hms(2021, 5, 1, 21, 59, 0).unwrap();
+        assert!(get_eligible_client_at(&grouped, &[], &evening).is_err());
+        let (picked, count, max) = get_eligible_client_at(&grouped, &[], &(evening + chrono::Duration::minutes(1))).unwrap();
+        assert_eq!((picked.name.as_str(), count, max), ("worker", 1, 2));
+
+        let legacy: Client = bson::from_document(bson::doc! { "Name": "old", "AvailabilityStart": "", "AvailabilityEnd": "", "MaximumJobs": 1, "Priority": 1, "Online": true, "IgnoreOnline": false }).unwrap();
+        assert!(legacy.capacity_schedule.is_empty());
+    }
+
+    #[test]
+    fn test_load_clients_from_file() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("avior_clients_{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{
+                "_id": { "$oid": "5f8d0d55b54764421b7156c3" },
+                "Name": "vdr-u", "AvailabilityStart": "08:00", "AvailabilityEnd": "18:00",
+                "MaximumJobs": 2, "Priority": 1, "Online": true, "IgnoreOnline": false,
+                "BlackoutStart": { "$date": "2021-05-01T10:00:00Z" }
+            }]"#,
+        )?;
+        let clients = load_clients_from_file(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].id, Some(parse_object_id("5f8d0d55b54764421b7156c3")?));
+        assert_eq!(clients[0].maximum_jobs, 2);
+        assert_eq!(clients[0].blackout_start.unwrap().0, Utc.with_ymd_and_hms(2021, 5, 1, 10, 0, 0).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_soft_priority_client() {
+        let now = Local::now();
+        let busy = client("busy", 1, 4, true);
+        let spare = client("spare", 2, 2, true);
+        let grouped = group_clients(vec![busy.clone(), spare.clone()], job_counts(&[(&busy, 3)]));
+
+        // strict groups keep feeding the busy client while it has a slot left
+        assert_eq!(get_eligible_client_at(&grouped, &[], &now).unwrap().0.name, "busy");
+        // a quarter of the slots free at weight 1 loses against all slots free at weight 1/2
+        assert_eq!(get_soft_priority_client(&grouped, &[], &now, priority_weight(1.0)).unwrap().0.name, "spare");
+        // a steep weight behaves like the strict groups
+        assert_eq!(get_soft_priority_client(&grouped, &[], &now, priority_weight(3.0)).unwrap().0.name, "busy");
+
+        let grouped = group_clients(vec![busy.clone()], job_counts(&[(&busy, 4)]));
+        assert!(get_soft_priority_client(&grouped, &[], &now, priority_weight(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_selection_hook() {
+        let now = Local::now();
+        let worker = client("worker", 1, 1, true);
+        let mut events = Vec::new();
+        let mut hook = |event: SelectionEvent| {
+            events.push(match event {
+                SelectionEvent::Selected { client, current_jobs, maximum_jobs } => format!("{} {}/{}", client.name, current_jobs, maximum_jobs),
+                SelectionEvent::Failed { error } => format!("{:?}", error.kind),
+            })
+        };
+        let idle = group_clients(vec![worker.clone()], job_counts(&[]));
+        assert!(get_eligible_client_observed(&idle, &[], &now, &mut hook).is_ok());
+        let full = group_clients(vec![worker.clone()], job_counts(&[(&worker, 1)]));
+        assert!(get_eligible_client_observed(&full, &[], &now, &mut hook).is_err());
+        assert_eq!(events, vec!["worker 0/1", "NoEligibleClient"]);
+    }
+
+    #[test]
+    fn test_allowed_clients() {
+        let now = Local::now();
+        let preferred = client("preferred", 1, 2, true);
+        let allowed = client("allowed", 2, 1, true);
+        let grouped = group_clients(vec![preferred.clone(), allowed.clone()], job_counts(&[]));
+
+        let unrestricted = Job::default();
+        assert_eq!(get_eligible_client_for_job(&grouped, &[], &unrestricted, &now).unwrap().0.name, "preferred");
+        let job = Job { allowed_clients: vec![allowed.id.clone().unwrap()], ..Default::default() };
+        assert_eq!(get_eligible_client_for_job(&grouped, &[], &job, &now).unwrap().0.name, "allowed");
+
+        // the only allowed client is full, the free preferred client must not be picked instead
+        let grouped = group_clients(vec![preferred, allowed.clone()], job_counts(&[(&allowed, 1)]));
+        let err = get_eligible_client_for_job(&grouped, &[], &job, &now).unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::NoEligibleClient);
+    }
+
+    #[test]
+    fn test_eligible_client_by_minutes() {
+        let now = Local::now();
+        let budgeted = Client { maximum_minutes: Some(120), ..client("budgeted", 1, 1, true) };
+        let counted = client("counted", 1, 2, true);
+        let backup = client("backup", 2, 5, true);
+        // budgeted already has its single job slot taken, which doesn't matter for the minute budget
+        let counts = job_counts(&[(&budgeted, 1), (&counted, 1)]);
+        let minutes = job_counts(&[(&budgeted, 30), (&counted, 60)]);
+        let grouped = group_clients(vec![budgeted, counted, backup], counts);
+        let job = |estimate| Job { estimated_minutes: Some(estimate), ..Default::default() };
+
+        let (chosen, load) = get_eligible_client_by_minutes(&grouped, &[], &minutes, &job(90), &now).unwrap();
+        assert_eq!((chosen.name.as_str(), load), ("budgeted", 30));
+        // a longer job no longer fits the budget and goes to the count capped client
+        let (chosen, load) = get_eligible_client_by_minutes(&grouped, &[], &minutes, &job(91), &now).unwrap();
+        assert_eq!((chosen.name.as_str(), load), ("counted", 60));
+    }
+
+    #[test]
+    fn test_error_source() {
+        let io_error = std::io::Error::other("disk on fire");
+        let err = InfuserError::with_source("flush failed", io_error);
+        assert_eq!(err.to_string(), "flush failed");
+        assert_eq!(err.source().unwrap().to_string(), "disk on fire");
+        assert!(InfuserError::new("no source").source().is_none());
+    }
+
+    #[test]
+    fn test_empty_fleet() {
+        let now = Local::now();
+        let empty = group_clients(Vec::new(), HashMap::new());
+        assert!(empty.is_empty());
+        let err = get_eligible_client_at(&empty, &[], &now).unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::EmptyFleet);
+        let err = get_weighted_random_client(&empty, &[], &now, &mut rand::thread_rng()).unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::EmptyFleet);
+
+        let full = client("full", 1, 1, true);
+        let grouped = group_clients(vec![full.clone()], job_counts(&[(&full, 1)]));
+        let err = get_eligible_client_at(&grouped, &[], &now).unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::NoEligibleClient);
+        assert_eq!(err.to_string(), "no eligible client found");
+    }
+
+    #[test]
+    #[cfg(feature = "mongodb")]
+    #[ignore = "requires a reachable mongodb instance"]
     fn test_insert() -> Result<(), Box<dyn Error>> {
+        let config = cfg::Config { db_name: "avior".to_string(), ..Default::default() };
         let mongo_client = db::connect("mongodb://192.168.178.75:27107")?;
-        if let Some(res) = db::get_clients(&mongo_client, &config.db_name)?.get(0) {
-            let iid = db::insert_job(&mongo_client, &config.db_name,
-                &mut db::Job {
+        if let Some(res) = db::get_clients(&mongo_client, config.clients_db())?.first() {
+            let iid = db::insert_job(&mongo_client, config.jobs_db(),
+                &crate::Job {
                     id: None,
                     path: "\\\\vdr-u\\SDuRec\\Recording\\exists\\Geheimnisvolle Wildblumen_2021-04-10-14-58-01-arte HD (AC3,deu).ts".to_string(),
                     name: "Geheimnisvolle Wildblumen".to_string(),
                     subtitle: "Blütenpracht im Wald".to_string(),
-                    assigned_client: res.into(),
-                    custom_parameters: Vec::new()
+                    assigned_client: config.assigned_client(res)?,
+                    ..Default::default()
             })?;
             println!("{}", iid);
         }
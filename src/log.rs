@@ -1,40 +1,325 @@
 use chrono;
+use chrono_tz::Tz;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::VecDeque;
 use std::error::Error;
-use std::fs::OpenOptions;
+use std::fmt::{self, Write as _};
+use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
+use std::path::Path;
 
 pub struct Logger {
-    buffer: Vec<String>,
+    buffer: VecDeque<Line>,
+    format: Format,
     kopfer: String,
+    max_buffered_lines: Option<usize>,
+    overflow: Overflow,
+    timezone: Option<Tz>,
 }
 
+/// Appends one CSV row per scheduling decision to a file, for analysis in a spreadsheet
+///
+/// The header row is written when the file is created or empty, existing files are appended to.
+/// Used by `scheduler::schedule_once_recorded`, but it can record decisions made anywhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionCsv {
+    path: String,
+}
+
+impl DecisionCsv {
+    pub const HEADER: &'static str = "timestamp,job_path,client,priority,current_jobs";
+
+    pub fn new(path: &str) -> Self {
+        DecisionCsv { path: path.to_owned() }
+    }
+
+    /// Appends a row, `current_jobs` being the client's job count including the scheduled job
+    ///
+    /// The timestamp is written as RFC 3339. Fields containing commas, quotes or line breaks are
+    /// quoted, quotes inside them doubled.
+    pub fn record<Tz: chrono::TimeZone>(
+        &self,
+        timestamp: &chrono::DateTime<Tz>,
+        job_path: &str,
+        client: &str,
+        priority: i32,
+        current_jobs: i32,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        Tz::Offset: fmt::Display,
+    {
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        let mut rows = String::new();
+        if file.metadata()?.len() == 0 {
+            writeln!(rows, "{}", Self::HEADER)?;
+        }
+        writeln!(
+            rows,
+            "{},{},{},{},{}",
+            timestamp.to_rfc3339(),
+            csv_field(job_path),
+            csv_field(client),
+            priority,
+            current_jobs
+        )?;
+        file.write_all(rows.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Append,
     Overwrite,
 }
 
+/// How a Logger writes its buffered lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// the message followed by its fields as `key=value` pairs
+    Plain,
+    /// one JSON object per line, the message under `message` and every field as its own key
+    Json,
+}
+
+/// A buffered message and the fields passed to `add_with_fields`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Line {
+    fn new(message: &str, fields: &[(&str, &str)]) -> Self {
+        let fields = fields.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        Line { message: message.to_owned(), fields }
+    }
+
+    fn render(&self, format: Format) -> String {
+        match format {
+            Format::Plain => {
+                let mut line = self.message.clone();
+                for (key, value) in &self.fields {
+                    line.push(' ');
+                    line.push_str(&plain_field(key, value));
+                }
+                line
+            }
+            Format::Json => {
+                let mut object = serde_json::Map::new();
+                object.insert("message".to_owned(), self.message.clone().into());
+                for (key, value) in &self.fields {
+                    object.insert(key.clone(), value.clone().into());
+                }
+                serde_json::Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+/// What a Logger does once its buffer holds more than `max_buffered_lines`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    /// drop the oldest buffered line, those lines are lost for good
+    DropOldest,
+    /// flush the buffer to `path`, keeping every line at the cost of a write
+    /// in the middle of what would otherwise have been a single block
+    Flush { path: String, mode: Mode },
+}
+
 pub trait Log {
     fn new(kopferino: &str) -> Self;
     fn add(&mut self, message: &str);
+    /// Appends a line with key-value context, by default the fields are appended to the message
+    /// as `key=value` pairs, values containing whitespace, quotes or `=` quoted
+    fn add_with_fields(&mut self, message: &str, fields: &[(&str, &str)]) {
+        self.add(&Line::new(message, fields).render(Format::Plain))
+    }
     fn clear(&mut self);
     fn flush(&mut self, path: &str, mode: Mode) -> Result<(), Box<dyn Error>>;
 }
 
-impl Logger {}
+impl Logger {
+    /// Caps the buffer at `max_lines` lines, see `Overflow` for what happens to the lines beyond
+    ///
+    /// Without a cap, a logger that is rarely flushed grows forever.
+    /// If an auto flush fails the error is printed and the oldest line is dropped instead.
+    pub fn with_max_buffered_lines(mut self, max_lines: usize, overflow: Overflow) -> Self {
+        self.max_buffered_lines = Some(max_lines);
+        self.overflow = overflow;
+        self
+    }
+
+    /// Writes buffered lines in `format`, `Format::Plain` unless set
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Takes the buffered lines out of the logger without writing them anywhere,
+    /// rendered in the logger's format
+    pub fn drain(&mut self) -> Vec<String> {
+        let format = self.format;
+        self.buffer.drain(..).map(|line| line.render(format)).collect()
+    }
+
+    fn push(&mut self, line: Line) {
+        println!("{}", line.render(self.format));
+        self.buffer.push_back(line);
+        self.handle_overflow()
+    }
+
+    /// Writes block timestamps in `timezone` instead of the local timezone of the system,
+    /// pass `Config::timezone` to log in the same timezone scheduling decisions are made in
+    pub fn with_timezone(mut self, timezone: Option<Tz>) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    /// The path of rotation slot `index` for the log file `base`
+    ///
+    /// Slot 0 is the active file itself, slot N is `{base}.{N}`, higher slots hold older logs.
+    pub fn rotated_path(base: &str, index: usize) -> String {
+        if index == 0 {
+            base.to_owned()
+        } else {
+            format!("{}.{}", base, index)
+        }
+    }
+
+    /// The path of rotation slot `index` when rotating with `rotate_compressed`,
+    /// rotated slots get an additional `.gz` extension: `{base}.{N}.gz`
+    pub fn compressed_rotated_path(base: &str, index: usize) -> String {
+        if index == 0 {
+            base.to_owned()
+        } else {
+            format!("{}.{}.gz", base, index)
+        }
+    }
+
+    /// Rotates the log file `base`, keeping up to `keep` rotated files
+    ///
+    /// Every file moves up one slot, see `rotated_path`, the file in slot `keep` is deleted and
+    /// the active file becomes slot 1, so the next flush starts a new file. Missing slots are skipped.
+    pub fn rotate(base: &str, keep: usize) -> Result<(), Box<dyn Error>> {
+        shift_rotated_files(base, keep, Logger::rotated_path)?;
+        if keep > 0 && Path::new(base).exists() {
+            fs::rename(base, Logger::rotated_path(base, 1))?;
+        }
+        Ok(())
+    }
+
+    /// Same as `rotate`, but gzips the active file into slot 1, see `compressed_rotated_path`
+    ///
+    /// The active file stays plain text, only rolled off files are compressed. The active file is
+    /// only removed once its compressed copy was written completely.
+    pub fn rotate_compressed(base: &str, keep: usize) -> Result<(), Box<dyn Error>> {
+        shift_rotated_files(base, keep, Logger::compressed_rotated_path)?;
+        if keep > 0 && Path::new(base).exists() {
+            let mut encoder = GzEncoder::new(fs::File::create(Logger::compressed_rotated_path(base, 1))?, Compression::default());
+            std::io::copy(&mut fs::File::open(base)?, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            fs::remove_file(base)?;
+        }
+        Ok(())
+    }
+
+    fn handle_overflow(&mut self) {
+        let max_lines = match self.max_buffered_lines {
+            Some(max_lines) if self.buffer.len() > max_lines => max_lines,
+            _ => return,
+        };
+        if let Overflow::Flush { path, mode } = self.overflow.clone() {
+            match self.flush(&path, mode) {
+                Ok(()) => return,
+                Err(e) => eprintln!("error auto flushing log to {}: {}", path, e),
+            }
+        }
+        while self.buffer.len() > max_lines {
+            self.buffer.pop_front();
+        }
+    }
+
+    /// Same as `flush`, but writes `header` instead of the logger's header for this block only
+    ///
+    /// Handy when one logger serves several jobs one after another, the stored header stays untouched.
+    pub fn flush_with_header(&mut self, path: &str, mode: Mode, header: &str) -> Result<(), Box<dyn Error>> {
+        let append = match mode {
+            Mode::Append => true,
+            Mode::Overwrite => false,
+        };
+        let block = self.format_block(header)?;
+        let mut logfile = OpenOptions::new()
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .create(true)
+            .open(self.expand_path(path))?;
+        logfile.write_all(block.as_bytes())?;
+        self.clear();
+        Ok(())
+    }
+
+    /// Expands the placeholders of a log path template
+    ///
+    /// `{date}` becomes the current date as `YYYY-MM-DD` in the logger's timezone, so flushing to
+    /// `scheduler-{date}.log` starts a new file every day. Paths without placeholders are returned as is.
+    /// Both `flush` and `flush_with_header` expand their path.
+    pub fn expand_path(&self, template: &str) -> String {
+        if !template.contains("{date}") {
+            return template.to_owned();
+        }
+        let date = match self.timezone {
+            Some(tz) => chrono::Utc::now().with_timezone(&tz).format("%Y-%m-%d").to_string(),
+            None => chrono::offset::Local::now().format("%Y-%m-%d").to_string(),
+        };
+        template.replace("{date}", &date)
+    }
+
+    /// Renders a complete log block: timestamp, header, buffered lines and a trailing blank line
+    fn format_block(&self, header: &str) -> Result<String, fmt::Error> {
+        let mut block = String::new();
+        let format = "%Y-%m-%d %H:%M:%S %z";
+        match self.timezone {
+            Some(tz) => writeln!(block, "{}", chrono::Utc::now().with_timezone(&tz).format(format))?,
+            None => writeln!(block, "{}", chrono::offset::Local::now().format(format))?,
+        }
+        writeln!(block, "{}", header)?;
+        for line in self.buffer.iter() {
+            writeln!(block, "{}", line.render(self.format))?;
+        }
+        writeln!(block)?;
+        Ok(block)
+    }
+}
 
 impl Log for Logger {
     /// Creates a new Logger instance
     fn new(kopferino: &str) -> Self {
         Logger {
-            buffer: Vec::new(),
+            buffer: VecDeque::new(),
+            format: Format::Plain,
             kopfer: kopferino.to_owned(),
+            max_buffered_lines: None,
+            overflow: Overflow::DropOldest,
+            timezone: None,
         }
     }
 
     /// Appends a line to the log buffer
     fn add(&mut self, message: &str) {
-        println!("{}", message);
-        self.buffer.push(message.into())
+        self.push(Line::new(message, &[]))
+    }
+
+    /// Appends a line with key-value context to the log buffer
+    ///
+    /// The fields are stored with the line. `Format::Plain` writes them after the message as
+    /// `key=value` pairs, values containing whitespace, quotes or `=` are quoted so the line can be
+    /// split reliably when parsing. `Format::Json` writes them as keys of the line's object, a field
+    /// named `message` replaces the message.
+    fn add_with_fields(&mut self, message: &str, fields: &[(&str, &str)]) {
+        self.push(Line::new(message, fields))
     }
 
     /// Clears the logging queue
@@ -44,26 +329,224 @@ impl Log for Logger {
 
     /// Flushes the log contents to disk
     ///
+    /// The whole block is written with a single write call,
+    /// so a crash during the flush doesn't leave a partial block behind.
+    ///
     /// ### Parameters:
-    /// - path: a valid OS filepath including the file extension
+    /// - path: a valid OS filepath including the file extension, may contain `{date}`, see `Logger::expand_path`
     /// - mode: a mode string being either
     fn flush(&mut self, path: &str, mode: Mode) -> Result<(), Box<dyn Error>> {
-        let append = match mode {
-            Mode::Append => true,
-            Mode::Overwrite => false,
-        };
-        let mut logfile = OpenOptions::new().write(true).append(append).create(true).open(path)?;
-        writeln!(
-            logfile,
-            "{}",
-            chrono::offset::Local::now().format("%Y-%m-%d %H:%M:%S %z").to_string()
-        )?;
-        writeln!(logfile, "{}", self.kopfer)?;
-        for line in self.buffer.iter() {
-            writeln!(logfile, "{}", line)?;
+        let header = self.kopfer.to_owned();
+        self.flush_with_header(path, mode, &header)
+    }
+}
+
+/// Deletes the file in slot `keep` and moves slots 1 to `keep - 1` up by one, keeping the active file in place
+fn shift_rotated_files(base: &str, keep: usize, path: fn(&str, usize) -> String) -> Result<(), Box<dyn Error>> {
+    if keep == 0 {
+        return remove_if_exists(base);
+    }
+    remove_if_exists(&path(base, keep))?;
+    for index in (1..keep).rev() {
+        let from = path(base, index);
+        if Path::new(&from).exists() {
+            fs::rename(&from, path(base, index + 1))?;
         }
-        writeln!(logfile, "")?;
-        self.clear();
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &str) -> Result<(), Box<dyn Error>> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn plain_field(key: &str, value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        format!("{}={}", key, value)
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_block() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("avior_flush_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut logger = Logger::new("header");
+        logger.add("first line");
+        logger.add("second line");
+        logger.flush(path, Mode::Overwrite)?;
+        logger.add("shorter");
+        logger.flush(path, Mode::Overwrite)?;
+        let contents = std::fs::read_to_string(path)?;
+        std::fs::remove_file(path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[1..], ["header", "shorter", ""]);
+        assert!(logger.buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut logger = Logger::new("header");
+        logger.add("first");
+        logger.add_with_fields("second", &[("client", "vdr-u")]);
+        assert_eq!(logger.drain(), vec!["first", "second client=vdr-u"]);
+        assert!(logger.drain().is_empty());
+    }
+
+    #[test]
+    fn test_timezone() -> Result<(), fmt::Error> {
+        let logger = Logger::new("header").with_timezone(Some(chrono_tz::Asia::Kolkata));
+        let block = logger.format_block("header")?;
+        assert!(block.lines().next().unwrap().ends_with(" +0530"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_with_header() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("avior_header_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut logger = Logger::new("header");
+        logger.add("job line");
+        logger.flush_with_header(path, Mode::Overwrite, "job 42")?;
+        logger.add("regular line");
+        logger.flush(path, Mode::Append)?;
+        let contents = std::fs::read_to_string(path)?;
+        std::fs::remove_file(path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[1..3], ["job 42", "job line"]);
+        assert_eq!(lines[5..7], ["header", "regular line"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decision_csv() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("avior_decisions_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let csv = DecisionCsv::new(path);
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2021-05-01T12:00:00+02:00")?;
+        csv.record(&timestamp, "D:\\Rec\\Show, Part 1.ts", "encoder", 1, 2)?;
+        csv.record(&timestamp, "D:\\Rec\\\"Quoted\".ts", "encoder", 2, 3)?;
+        let contents = std::fs::read_to_string(path)?;
+        std::fs::remove_file(path)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                DecisionCsv::HEADER,
+                "2021-05-01T12:00:00+02:00,\"D:\\Rec\\Show, Part 1.ts\",encoder,1,2",
+                "2021-05-01T12:00:00+02:00,\"D:\\Rec\\\"\"Quoted\"\".ts\",encoder,2,3",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let logger = Logger::new("header").with_timezone(Some(chrono_tz::UTC));
+        let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+        assert_eq!(logger.expand_path("scheduler-{date}.log"), format!("scheduler-{}.log", today));
+        assert_eq!(logger.expand_path("scheduler.log"), "scheduler.log");
+    }
+
+    #[test]
+    fn test_add_with_fields() {
+        let mut logger = Logger::new("header");
+        logger.add_with_fields("scheduled job", &[("client", "vdr-u"), ("path", "C:\\rec\\a b.ts"), ("note", "")]);
+        assert_eq!(logger.drain(), vec!["scheduled job client=vdr-u path=\"C:\\\\rec\\\\a b.ts\" note=\"\""]);
+    }
+
+    #[test]
+    fn test_add_with_fields_json() -> Result<(), serde_json::Error> {
+        let mut logger = Logger::new("header").with_format(Format::Json);
+        logger.add("plain line");
+        logger.add_with_fields("scheduled job", &[("client", "vdr-u"), ("path", "C:\\rec\\a b.ts")]);
+        let lines = logger.drain();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&lines[0])?, serde_json::json!({ "message": "plain line" }));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&lines[1])?,
+            serde_json::json!({ "message": "scheduled job", "client": "vdr-u", "path": "C:\\rec\\a b.ts" })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("avior_rotate_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let base = dir.join("infuser.log");
+        let base = base.to_str().unwrap();
+        assert_eq!(Logger::rotated_path(base, 0), base);
+        assert_eq!(Logger::rotated_path(base, 2), format!("{}.2", base));
+
+        for generation in ["first", "second", "third"] {
+            fs::write(base, generation)?;
+            Logger::rotate(base, 2)?;
+        }
+        assert!(!Path::new(base).exists());
+        assert_eq!(fs::read_to_string(Logger::rotated_path(base, 1))?, "third");
+        assert_eq!(fs::read_to_string(Logger::rotated_path(base, 2))?, "second");
+        assert!(!Path::new(&Logger::rotated_path(base, 3)).exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_compressed() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("avior_rotate_gz_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let base = dir.join("infuser.log");
+        let base = base.to_str().unwrap();
+        assert_eq!(Logger::compressed_rotated_path(base, 1), format!("{}.1.gz", base));
+
+        for generation in ["first", "second"] {
+            fs::write(base, generation)?;
+            Logger::rotate_compressed(base, 3)?;
+        }
+        assert!(!Path::new(base).exists());
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(Logger::compressed_rotated_path(base, 2))?).read_to_string(&mut contents)?;
+        assert_eq!(contents, "first");
+        assert!(Path::new(&Logger::compressed_rotated_path(base, 1)).exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_buffered_lines() -> Result<(), Box<dyn Error>> {
+        let mut logger = Logger::new("header").with_max_buffered_lines(2, Overflow::DropOldest);
+        logger.add("one");
+        logger.add("two");
+        logger.add("three");
+        assert_eq!(logger.drain(), vec!["two", "three"]);
+
+        let path = std::env::temp_dir().join(format!("avior_overflow_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let overflow = Overflow::Flush { path: path.to_owned(), mode: Mode::Overwrite };
+        let mut logger = Logger::new("header").with_max_buffered_lines(2, overflow);
+        logger.add("one");
+        logger.add("two");
+        logger.add("three");
+        assert!(logger.buffer.is_empty());
+        let contents = std::fs::read_to_string(path)?;
+        std::fs::remove_file(path)?;
+        assert_eq!(contents.lines().collect::<Vec<_>>()[1..], ["header", "one", "two", "three", ""]);
         Ok(())
     }
 }
@@ -0,0 +1,310 @@
+use crate::cfg::Config;
+use crate::log::DecisionCsv;
+use crate::{db, get_eligible_client_for_job, group_clients, Client, InfuserError, InfuserErrorKind, Job, MongoClient, MongoError};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+/// The outcome of a scheduling tick, see `schedule_once`
+#[derive(Debug, Clone)]
+pub enum ScheduleOutcome {
+    /// the job was assigned and inserted
+    Scheduled(ScheduledJob),
+    /// a job with the same path is already queued, nothing was inserted
+    AlreadyQueued { timings: TickTimings },
+}
+
+impl ScheduleOutcome {
+    /// How long the phases of the tick took
+    pub fn timings(&self) -> &TickTimings {
+        match self {
+            ScheduleOutcome::Scheduled(scheduled) => &scheduled.timings,
+            ScheduleOutcome::AlreadyQueued { timings } => timings,
+        }
+    }
+}
+
+/// How long each phase of a `schedule_once` tick took
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TickTimings {
+    /// the pause switch, see `db::is_scheduler_paused`
+    pub reading_settings: Duration,
+    pub reading_clients: Duration,
+    /// the `get_machine_jobcount` aggregation
+    pub counting_jobs: Duration,
+    pub grouping: Duration,
+    pub selecting: Duration,
+    /// the duplicate check and the insert
+    pub inserting: Duration,
+}
+
+impl TickTimings {
+    pub fn total(&self) -> Duration {
+        self.reading_settings + self.reading_clients + self.counting_jobs + self.grouping + self.selecting + self.inserting
+    }
+}
+
+/// Everything worth logging about a job `schedule_once` inserted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledJob {
+    /// id of the inserted job
+    pub job_id: String,
+    pub client_name: String,
+    /// the priority group the client was picked from
+    pub priority: i32,
+    /// the client's job count including the inserted job
+    pub current_jobs: i32,
+    /// the client's maximum job count at the time of scheduling
+    pub maximum_jobs: i32,
+    /// whether every client of a higher priority group was full or unavailable,
+    /// so the job went to a lower priority group than the regular one
+    pub fell_back: bool,
+    pub timings: TickTimings,
+}
+
+/// A wall clock budget shared by the steps of a scheduling tick
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Self {
+        Deadline { at: Instant::now() + budget }
+    }
+
+    /// The time left for `step`, or a `Timeout` error naming the step if the budget is used up
+    pub fn remaining(&self, step: &str) -> Result<Duration, InfuserError> {
+        let remaining = self.at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(InfuserError::with_kind(
+                InfuserErrorKind::Timeout,
+                &format!("scheduling deadline exceeded before {}", step),
+            ));
+        }
+        Ok(remaining)
+    }
+}
+
+/// Runs one scheduling tick: reads the fleet and job counts, picks a client and inserts `job` for it
+///
+/// The whole tick has to finish within `budget`. The reads, the pause switch included, and the duplicate
+/// check are sent with the remaining budget as their server side `max_time`, the insert with it as its
+/// write concern timeout, and the budget is checked again before every step, so a slow database
+/// results in a `Timeout` error instead of a hanging scheduler loop. An insert that timed out may still have been written.
+/// Only clients the job allows are considered, see `Job::allowed_clients`.
+/// Time based rules are evaluated in the timezone of `cfg`, see `Config::now`.
+/// Fails with `NoEligibleClient` or `EmptyFleet` if no client can take the job, and with `Paused`
+/// without touching anything else if assignment is paused, see `db::set_scheduler_paused`.
+/// The low level selection and insert functions keep returning their own, simpler results.
+/// The outcome carries how long each phase took, see `TickTimings`.
+pub fn schedule_once(
+    mongo_client: &MongoClient,
+    cfg: &Config,
+    job: Job,
+    ignored_clients: &[Client],
+    budget: Duration,
+) -> Result<ScheduleOutcome, Box<dyn Error>> {
+    schedule_once_recorded(mongo_client, cfg, job, ignored_clients, budget, None)
+}
+
+/// Same as `schedule_once`, additionally appending every scheduled job to `decisions` if given
+///
+/// Only inserted jobs are recorded. The job is already inserted when the row is written, so a
+/// failing write is reported on stderr instead of failing the tick.
+pub fn schedule_once_recorded(
+    mongo_client: &MongoClient,
+    cfg: &Config,
+    mut job: Job,
+    ignored_clients: &[Client],
+    budget: Duration,
+    decisions: Option<&DecisionCsv>,
+) -> Result<ScheduleOutcome, Box<dyn Error>> {
+    let deadline = Deadline::after(budget);
+    let mut timings = TickTimings::default();
+    let mut phase = Instant::now();
+    let mut lap = || {
+        let elapsed = phase.elapsed();
+        phase = Instant::now();
+        elapsed
+    };
+    if db::is_scheduler_paused_within(mongo_client, cfg.jobs_db(), deadline.remaining("reading the pause switch")?)? {
+        return Err(Box::new(InfuserError::with_kind(InfuserErrorKind::Paused, "scheduling is paused")));
+    }
+    timings.reading_settings = lap();
+    let clients = db::get_clients_within(mongo_client, cfg.clients_db(), deadline.remaining("reading clients")?)?;
+    timings.reading_clients = lap();
+    let job_counts = db::get_machine_jobcount_within(mongo_client, cfg.jobs_db(), deadline.remaining("counting jobs")?)?;
+    timings.counting_jobs = lap();
+    let grouped = group_clients(clients, job_counts);
+    timings.grouping = lap();
+    let now = cfg.now()?;
+    let (client, current_jobs, maximum_jobs) = get_eligible_client_for_job(&grouped, ignored_clients, &job, &now)?;
+    timings.selecting = lap();
+    job.assigned_client = cfg.assigned_client(client)?;
+    let inserted = insert_if_absent_within(
+        &deadline,
+        |max_time| db::job_exists_within(mongo_client, cfg.jobs_db(), &job.path, max_time),
+        |w_timeout| db::insert_job_within(mongo_client, cfg.jobs_db(), &job, w_timeout),
+    )?;
+    timings.inserting = lap();
+    if let (Some(decisions), Some(_)) = (decisions, &inserted) {
+        if let Err(e) = decisions.record(&now, &job.path, &client.name, client.priority, current_jobs + 1) {
+            eprintln!("error recording scheduling decision in scheduler::schedule_once_recorded: {:?}", e);
+        }
+    }
+    match inserted {
+        Some(job_id) => Ok(ScheduleOutcome::Scheduled(ScheduledJob {
+            job_id,
+            client_name: client.name.to_owned(),
+            priority: client.priority,
+            current_jobs: current_jobs + 1,
+            maximum_jobs,
+            fell_back: grouped.keys().next().is_some_and(|first| *first != client.priority),
+            timings,
+        })),
+        None => Ok(ScheduleOutcome::AlreadyQueued { timings }),
+    }
+}
+
+/// The duplicate check and the insert of a tick, each bounded by what is left of `deadline`
+///
+/// The insert isn't sent once the budget was used up by the duplicate check.
+fn insert_if_absent_within(
+    deadline: &Deadline,
+    exists: impl FnOnce(Duration) -> Result<bool, MongoError>,
+    insert: impl FnOnce(Duration) -> Result<String, MongoError>,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if exists(deadline.remaining("checking for a queued job")?)? {
+        return Ok(None);
+    }
+    Ok(Some(insert(deadline.remaining("inserting the job")?)?))
+}
+
+/// What a `Scheduler` reports to the receiver of its events
+#[derive(Debug, Clone)]
+pub enum SchedulerEvent {
+    /// the job was inserted for a client
+    Scheduled(ScheduledJob),
+    /// a job with the path is already queued, nothing was inserted
+    Skipped { path: String },
+    /// no client could take the job, `kind` is `EmptyFleet`, `NoEligibleClient` or `GlobalCapacityReached`
+    FleetFull { path: String, kind: InfuserErrorKind },
+}
+
+/// Runs scheduling ticks and pushes an event for each of them, e.g. to a live dashboard
+///
+/// Events go through a bounded channel, so a consumer that lags behind can't make the scheduler
+/// run out of memory: once `capacity` events are waiting, further events are dropped and counted,
+/// see `dropped_events`, and the tick itself is not slowed down. A dropped receiver is treated the same.
+/// Ticks failing for other reasons, e.g. a database error, a timeout or a paused scheduler,
+/// only return their error and send no event.
+pub struct Scheduler {
+    cfg: Config,
+    decisions: Option<DecisionCsv>,
+    events: SyncSender<SchedulerEvent>,
+    dropped_events: AtomicUsize,
+}
+
+impl Scheduler {
+    /// Creates a scheduler and the receiving end of its events, holding at most `capacity` unread events
+    pub fn new(cfg: Config, capacity: usize) -> (Self, Receiver<SchedulerEvent>) {
+        let (events, receiver) = mpsc::sync_channel(capacity);
+        let scheduler = Scheduler { cfg, decisions: None, events, dropped_events: AtomicUsize::new(0) };
+        (scheduler, receiver)
+    }
+
+    /// Records every scheduled job to `decisions` as well, see `schedule_once_recorded`
+    pub fn with_decisions(mut self, decisions: DecisionCsv) -> Self {
+        self.decisions = Some(decisions);
+        self
+    }
+
+    /// Runs one tick with `schedule_once_recorded` and sends its event
+    pub fn tick(&self, mongo_client: &MongoClient, job: Job, ignored_clients: &[Client], budget: Duration) -> Result<ScheduleOutcome, Box<dyn Error>> {
+        let path = job.path.to_owned();
+        let result = schedule_once_recorded(mongo_client, &self.cfg, job, ignored_clients, budget, self.decisions.as_ref());
+        match &result {
+            Ok(ScheduleOutcome::Scheduled(scheduled)) => self.emit(SchedulerEvent::Scheduled(scheduled.clone())),
+            Ok(ScheduleOutcome::AlreadyQueued { .. }) => self.emit(SchedulerEvent::Skipped { path }),
+            Err(e) => match e.downcast_ref::<InfuserError>().map(|e| e.kind) {
+                Some(kind @ InfuserErrorKind::EmptyFleet)
+                | Some(kind @ InfuserErrorKind::NoEligibleClient)
+                | Some(kind @ InfuserErrorKind::GlobalCapacityReached) => self.emit(SchedulerEvent::FleetFull { path, kind }),
+                _ => (),
+            },
+        }
+        result
+    }
+
+    /// The number of events dropped because the channel was full or the receiver was gone
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    fn emit(&self, event: SchedulerEvent) {
+        // full or disconnected, either way nobody is going to read the event in time
+        if self.events.try_send(event).is_err() {
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let deadline = Deadline::after(Duration::from_secs(60));
+        assert!(deadline.remaining("reading clients").unwrap() > Duration::from_secs(59));
+
+        let expired = Deadline::after(Duration::ZERO);
+        let err = expired.remaining("counting jobs").unwrap_err();
+        assert_eq!(err.kind, InfuserErrorKind::Timeout);
+        assert_eq!(err.to_string(), "scheduling deadline exceeded before counting jobs");
+    }
+
+    #[test]
+    fn test_deadline_exceeded_before_insert() {
+        let deadline = Deadline::after(Duration::from_millis(20));
+        let mut inserted = false;
+        let result = insert_if_absent_within(
+            &deadline,
+            |_| {
+                std::thread::sleep(Duration::from_millis(30));
+                Ok(false)
+            },
+            |_| {
+                inserted = true;
+                Ok("id".to_string())
+            },
+        );
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<InfuserError>().unwrap().kind, InfuserErrorKind::Timeout);
+        assert_eq!(err.to_string(), "scheduling deadline exceeded before inserting the job");
+        assert!(!inserted);
+    }
+
+    #[test]
+    fn test_event_backpressure() {
+        let (scheduler, receiver) = Scheduler::new(Config::default(), 2);
+        for path in ["a", "b", "c"].iter() {
+            scheduler.emit(SchedulerEvent::Skipped { path: path.to_string() });
+        }
+        assert_eq!(scheduler.dropped_events(), 1);
+        let paths: Vec<String> = receiver
+            .try_iter()
+            .filter_map(|event| match event {
+                SchedulerEvent::Skipped { path } => Some(path),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(paths, vec!["a", "b"]);
+
+        drop(receiver);
+        scheduler.emit(SchedulerEvent::Skipped { path: "d".to_string() });
+        assert_eq!(scheduler.dropped_events(), 2);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    get_eligible_client_for_job, get_eligible_client_preferring_online, get_soft_priority_client, get_weighted_random_client, priority_weight, select_least_loaded, Client,
+    InfuserError, Job,
+};
+use chrono::{DateTime, FixedOffset, Offset, TimeZone};
+use std::collections::{BTreeMap, HashMap};
+
+/// Everything a `SelectionStrategy` may base its decision on besides the clients themselves
+pub struct SelectionContext<'c> {
+    /// clients that must not be picked
+    pub ignored_clients: &'c [Client],
+    /// the time to evaluate availability windows, blackouts and capacity schedules at
+    pub now: DateTime<FixedOffset>,
+    /// the job a client is selected for, if the strategy should take it into account
+    pub job: Option<&'c Job>,
+}
+
+impl<'c> SelectionContext<'c> {
+    pub fn new<Tz: TimeZone>(ignored_clients: &'c [Client], now: &DateTime<Tz>) -> Self {
+        SelectionContext {
+            ignored_clients,
+            now: now.with_timezone(&now.offset().fix()),
+            job: None,
+        }
+    }
+
+    pub fn with_job(mut self, job: &'c Job) -> Self {
+        self.job = Some(job);
+        self
+    }
+}
+
+/// Decides which client receives the next job
+///
+/// Implement this to plug custom selection logic into code that takes a strategy instead of
+/// forking `get_eligible_client`. Implementations return the client, its current job count and
+/// its maximum job count, like `get_eligible_client` does.
+pub trait SelectionStrategy {
+    fn select<'a>(
+        &self,
+        grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+        context: &SelectionContext,
+    ) -> Result<(&'a Client, i32, i32), InfuserError>;
+}
+
+/// The least loaded client of the first priority group with an eligible client, see `get_eligible_client`
+///
+/// If the context has a job, its `allowed_clients` are honored, see `get_eligible_client_for_job`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LeastLoaded;
+
+impl SelectionStrategy for LeastLoaded {
+    fn select<'a>(
+        &self,
+        grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+        context: &SelectionContext,
+    ) -> Result<(&'a Client, i32, i32), InfuserError> {
+        match context.job {
+            Some(job) => get_eligible_client_for_job(grouped_clients, context.ignored_clients, job, &context.now),
+            None => select_least_loaded(grouped_clients, context.ignored_clients, &context.now, |_| true),
+        }
+    }
+}
+
+/// Like `LeastLoaded`, but ties go to clients that are actually online, see `get_eligible_client_preferring_online`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PreferOnline;
+
+impl SelectionStrategy for PreferOnline {
+    fn select<'a>(
+        &self,
+        grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+        context: &SelectionContext,
+    ) -> Result<(&'a Client, i32, i32), InfuserError> {
+        get_eligible_client_preferring_online(grouped_clients, context.ignored_clients, &context.now)
+    }
+}
+
+/// A random client weighted by remaining capacity, see `get_weighted_random_client`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WeightedRandom;
+
+impl SelectionStrategy for WeightedRandom {
+    fn select<'a>(
+        &self,
+        grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+        context: &SelectionContext,
+    ) -> Result<(&'a Client, i32, i32), InfuserError> {
+        get_weighted_random_client(grouped_clients, context.ignored_clients, &context.now, &mut rand::thread_rng())
+    }
+}
+
+/// Priorities as weights instead of strict groups, see `get_soft_priority_client` and `priority_weight`
+#[derive(Debug, Clone, Copy)]
+pub struct SoftPriority {
+    pub exponent: f64,
+}
+
+impl SelectionStrategy for SoftPriority {
+    fn select<'a>(
+        &self,
+        grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+        context: &SelectionContext,
+    ) -> Result<(&'a Client, i32, i32), InfuserError> {
+        get_soft_priority_client(grouped_clients, context.ignored_clients, &context.now, priority_weight(self.exponent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_clients;
+    use chrono::Utc;
+
+    /// picks the client with the alphabetically last name, ignoring every rule
+    struct LastByName;
+
+    impl SelectionStrategy for LastByName {
+        fn select<'a>(
+            &self,
+            grouped_clients: &'a BTreeMap<i32, HashMap<Client, Option<i32>>>,
+            _: &SelectionContext,
+        ) -> Result<(&'a Client, i32, i32), InfuserError> {
+            let (client, count) = grouped_clients
+                .values()
+                .flatten()
+                .max_by(|a, b| a.0.name.cmp(&b.0.name))
+                .ok_or_else(|| InfuserError::new("no clients"))?;
+            Ok((client, count.unwrap_or(0), client.maximum_jobs))
+        }
+    }
+
+    fn client(name: &str, priority: i32) -> Client {
+        Client {
+            id: Some(bson::oid::ObjectId::new()),
+            name: name.to_string(),
+            maximum_jobs: 2,
+            priority,
+            online: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        let grouped = group_clients(vec![client("alpha", 1), client("beta", 2)], HashMap::new());
+        let context = SelectionContext::new(&[], &Utc::now());
+        let strategies: Vec<(&dyn SelectionStrategy, &str)> = vec![
+            (&LeastLoaded, "alpha"),
+            (&PreferOnline, "alpha"),
+            (&WeightedRandom, "alpha"),
+            (&SoftPriority { exponent: 1.0 }, "alpha"),
+            (&LastByName, "beta"),
+        ];
+        for (strategy, expected) in strategies {
+            assert_eq!(strategy.select(&grouped, &context).unwrap().0.name, expected);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::Job;
+use bson::oid::ObjectId;
+use rand::Rng;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// the longest a poll sleeps before checking the cancellation token again
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A source workers claim their jobs from, see `db::MongoJobStore`
+pub trait JobStore {
+    /// Claims the next job for `client_id`, `None` if there is nothing to do right now
+    fn claim_next_job(&self, client_id: &ObjectId) -> Result<Option<Job>, Box<dyn Error>>;
+}
+
+/// Polls `store` until a job for `client_id` is claimed
+///
+/// Between empty polls the worker sleeps for `interval` plus a random delay of up to `jitter`,
+/// so workers started at the same time don't keep hitting the database in lockstep.
+/// Setting `cancel` stops polling within `CANCEL_CHECK_INTERVAL`, in which case `None` is returned.
+/// Errors of the store are returned right away, retrying is up to the caller.
+pub fn poll_for_job<S: JobStore + ?Sized>(
+    store: &S,
+    client_id: &ObjectId,
+    interval: Duration,
+    jitter: Duration,
+    cancel: &AtomicBool,
+) -> Result<Option<Job>, Box<dyn Error>> {
+    let mut rng = rand::thread_rng();
+    while !cancel.load(Ordering::Relaxed) {
+        if let Some(job) = store.claim_next_job(client_id)? {
+            return Ok(Some(job));
+        }
+        let mut remaining = interval + jitter.mul_f64(rng.gen::<f64>());
+        while !remaining.is_zero() && !cancel.load(Ordering::Relaxed) {
+            let step = remaining.min(CANCEL_CHECK_INTERVAL);
+            thread::sleep(step);
+            remaining -= step;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// hands out a job once it has been polled `ready_after` times
+    struct CountingStore {
+        polls: Cell<u32>,
+        ready_after: u32,
+    }
+
+    impl JobStore for CountingStore {
+        fn claim_next_job(&self, _: &ObjectId) -> Result<Option<Job>, Box<dyn Error>> {
+            self.polls.set(self.polls.get() + 1);
+            if self.polls.get() >= self.ready_after {
+                Ok(Some(Job { name: "job".to_string(), ..Default::default() }))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    #[test]
+    fn test_poll_for_job() -> Result<(), Box<dyn Error>> {
+        let id = ObjectId::new();
+        let store = CountingStore { polls: Cell::new(0), ready_after: 3 };
+        let not_cancelled = AtomicBool::new(false);
+        let job = poll_for_job(&store, &id, Duration::from_millis(1), Duration::from_millis(1), &not_cancelled)?;
+        assert_eq!(job.unwrap().name, "job");
+        assert_eq!(store.polls.get(), 3);
+
+        let cancelled = AtomicBool::new(true);
+        let store = CountingStore { polls: Cell::new(0), ready_after: 1 };
+        assert!(poll_for_job(&store, &id, Duration::from_secs(60), Duration::ZERO, &cancelled)?.is_none());
+        assert_eq!(store.polls.get(), 0);
+        Ok(())
+    }
+}